@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sos_alloc_host::{bump::BumpAllocator, check_ops, Locked, Op};
+
+fuzz_target!(|data: &[u8]| {
+    let ops = Op::decode(data);
+    if let Err(e) = check_ops::<Locked<BumpAllocator>>(&ops, 64 * 1024) {
+        panic!("{}", e);
+    }
+});
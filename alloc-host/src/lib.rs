@@ -0,0 +1,203 @@
+//! The kernel heap allocators built for the host.
+//!
+//! The allocator sources are shared with the kernel through `#[path]`, so
+//! they are tested exactly as they run in sOS, only over a plain byte
+//! arena instead of the mapped kernel heap.
+
+#![feature(const_in_array_repeat_expressions)]
+
+extern crate alloc;
+
+#[path = "../../kernel/src/allocator/common.rs"]
+mod common;
+#[path = "../../kernel/src/allocator/bump.rs"]
+pub mod bump;
+#[path = "../../kernel/src/allocator/pool.rs"]
+pub mod pool;
+#[path = "../../kernel/src/allocator/block.rs"]
+pub mod block;
+#[path = "../../kernel/src/allocator/buddy.rs"]
+pub mod buddy;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use common::align_up;
+use std::collections::BTreeMap;
+
+pub use common::{HeapStatistics, HeapStats, Locked, Reclaim};
+
+const ARENA_ALIGN: usize = 4096;
+
+/// page aligned memory standing in for the kernel heap
+pub struct Arena {
+    start: *mut u8,
+    size: usize,
+}
+
+impl Arena {
+    pub fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, ARENA_ALIGN).unwrap();
+        let start = unsafe { std::alloc::alloc(layout) };
+        assert!(!start.is_null(), "failed to allocate arena");
+        Arena { start, size }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.size, ARENA_ALIGN).unwrap();
+        unsafe { std::alloc::dealloc(self.start, layout) }
+    }
+}
+
+/// a heap allocator that can be set up over an arena
+pub trait ArenaAllocator: GlobalAlloc + HeapStatistics + Reclaim {
+    const NAME: &'static str;
+
+    fn with_arena(arena: &Arena) -> Self;
+}
+
+macro_rules! arena_allocator {
+    ($name:expr, $allocator:ty) => {
+        impl ArenaAllocator for Locked<$allocator> {
+            const NAME: &'static str = $name;
+
+            fn with_arena(arena: &Arena) -> Self {
+                let heap = Locked::new(<$allocator>::new());
+                unsafe {
+                    heap.lock().init(arena.start(), arena.size());
+                }
+                heap
+            }
+        }
+    };
+}
+
+arena_allocator!("bump", bump::BumpAllocator);
+arena_allocator!("pool", pool::PoolAllocator);
+arena_allocator!("block", block::BlockAllocator);
+arena_allocator!("buddy", buddy::BuddyAllocator);
+
+pub const MAX_ALIGN_SHIFT: u8 = 12;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    /// allocate `size` bytes aligned to `1 << align_shift`
+    Alloc { size: usize, align_shift: u8 },
+    /// free the live allocation at this index, modulo the live count
+    Free(usize),
+    /// run the allocator's reclaim, as the kernel does before giving up
+    Reclaim,
+}
+
+impl Op {
+    /// turn fuzzer input into a sequence of operations
+    pub fn decode(data: &[u8]) -> Vec<Op> {
+        data.chunks_exact(4)
+            .map(|chunk| match chunk[0] % 8 {
+                7 => Op::Reclaim,
+                n if n % 2 == 0 => Op::Alloc {
+                    size: u16::from_le_bytes([chunk[1], chunk[2]]) as usize % 8192 + 1,
+                    align_shift: chunk[3] % (MAX_ALIGN_SHIFT + 1),
+                },
+                _ => Op::Free(u16::from_le_bytes([chunk[1], chunk[2]]) as usize),
+            })
+            .collect()
+    }
+}
+
+struct Allocation {
+    ptr: *mut u8,
+    layout: Layout,
+    fill: u8,
+}
+
+/// run `ops` against a fresh allocator of type `A` and check that it never
+/// hands out overlapping, misaligned or foreign memory, and that every byte
+/// is reclaimed once everything has been freed.
+pub fn check_ops<A: ArenaAllocator>(ops: &[Op], arena_size: usize) -> Result<(), String> {
+    let arena = Arena::new(arena_size);
+    let heap = A::with_arena(&arena);
+    let mut live: Vec<Allocation> = Vec::new();
+    // start address to end address of every live allocation
+    let mut ranges: BTreeMap<usize, usize> = BTreeMap::new();
+
+    for (step, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Alloc { size, align_shift } => {
+                let layout = Layout::from_size_align(size, 1 << align_shift).unwrap();
+                let ptr = unsafe { heap.alloc(layout) };
+                if ptr.is_null() {
+                    continue;
+                }
+
+                let start = ptr as usize;
+                let end = start + size;
+                if start % layout.align() != 0 {
+                    return Err(format!("step {}: {:p} is not aligned for {:?}", step, ptr, layout));
+                }
+                if start < arena.start() || end > arena.start() + arena.size() {
+                    return Err(format!("step {}: {:p} is outside of the arena", step, ptr));
+                }
+                if let Some((&other, &other_end)) = ranges.range(..end).next_back() {
+                    if other_end > start {
+                        return Err(format!("step {}: {:#x}..{:#x} overlaps {:#x}..{:#x}",
+                                           step, start, end, other, other_end));
+                    }
+                }
+
+                let fill = step as u8;
+                unsafe { ptr.write_bytes(fill, size) };
+                ranges.insert(start, end);
+                live.push(Allocation { ptr, layout, fill });
+            }
+            Op::Free(index) => {
+                if live.is_empty() {
+                    continue;
+                }
+                let allocation = live.swap_remove(index % live.len());
+                free(&heap, &mut ranges, allocation)
+                    .map_err(|e| format!("step {}: {}", step, e))?;
+            }
+            Op::Reclaim => {
+                heap.reclaim();
+            }
+        }
+
+        let stats = heap.heap_stats();
+        if stats.bytes_in_use + stats.free_bytes != arena.size() {
+            return Err(format!("step {}: {} bytes in use and {} bytes free in a {} byte heap",
+                               step, stats.bytes_in_use, stats.free_bytes, arena.size()));
+        }
+    }
+
+    for allocation in live.drain(..) {
+        free(&heap, &mut ranges, allocation)?;
+    }
+    let stats = heap.heap_stats();
+    if stats.bytes_in_use != 0 || stats.free_bytes != arena.size() {
+        return Err(format!("{} allocator leaked memory: {:?}", A::NAME, stats));
+    }
+    Ok(())
+}
+
+fn free<A: GlobalAlloc>(heap: &A, ranges: &mut BTreeMap<usize, usize>, allocation: Allocation)
+    -> Result<(), String>
+{
+    let Allocation { ptr, layout, fill } = allocation;
+    let contents = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+    if let Some(offset) = contents.iter().position(|&byte| byte != fill) {
+        return Err(format!("{:p} was overwritten at offset {} while in use", ptr, offset));
+    }
+    ranges.remove(&(ptr as usize));
+    unsafe { heap.dealloc(ptr, layout) };
+    Ok(())
+}
+
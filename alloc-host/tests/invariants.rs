@@ -0,0 +1,122 @@
+use proptest::prelude::*;
+use sos_alloc_host::{block::BlockAllocator, buddy::BuddyAllocator, bump::BumpAllocator,
+                     check_ops, pool::PoolAllocator, Arena, ArenaAllocator, HeapStatistics,
+                     Locked, Op, Reclaim, MAX_ALIGN_SHIFT};
+use std::alloc::{GlobalAlloc, Layout};
+
+const ARENA_SIZE: usize = 256 * 1024;
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (1usize..4096, 0..=MAX_ALIGN_SHIFT)
+            .prop_map(|(size, align_shift)| Op::Alloc { size, align_shift }),
+        any::<usize>().prop_map(Op::Free),
+        Just(Op::Reclaim),
+    ]
+}
+
+fn check<A: ArenaAllocator>(ops: &[Op]) -> Result<(), TestCaseError> {
+    check_ops::<A>(ops, ARENA_SIZE).map_err(TestCaseError::fail)
+}
+
+proptest! {
+    #[test]
+    fn bump_invariants(ops in prop::collection::vec(op(), 0..256)) {
+        check::<Locked<BumpAllocator>>(&ops)?;
+    }
+
+    #[test]
+    fn pool_invariants(ops in prop::collection::vec(op(), 0..256)) {
+        check::<Locked<PoolAllocator>>(&ops)?;
+    }
+
+    #[test]
+    fn block_invariants(ops in prop::collection::vec(op(), 0..256)) {
+        check::<Locked<BlockAllocator>>(&ops)?;
+    }
+
+    #[test]
+    fn buddy_invariants(ops in prop::collection::vec(op(), 0..256)) {
+        check::<Locked<BuddyAllocator>>(&ops)?;
+    }
+}
+
+#[test]
+fn exhaust_and_reclaim() {
+    // fill the heap with small blocks, free all of them, then do it again
+    let mut ops = Vec::new();
+    for _ in 0..2 {
+        ops.extend((0..ARENA_SIZE / 64).map(|_| Op::Alloc { size: 48, align_shift: 3 }));
+        ops.extend((0..ARENA_SIZE / 64).map(|_| Op::Free(0)));
+    }
+    check_ops::<Locked<PoolAllocator>>(&ops, ARENA_SIZE).unwrap();
+    check_ops::<Locked<BlockAllocator>>(&ops, ARENA_SIZE).unwrap();
+    check_ops::<Locked<BuddyAllocator>>(&ops, ARENA_SIZE).unwrap();
+    check_ops::<Locked<BumpAllocator>>(&ops, ARENA_SIZE).unwrap();
+}
+
+#[test]
+fn reclaim_defragments() {
+    // small blocks fill the heap, once freed only reclaim makes them usable
+    // for a big allocation again
+    fn fill_free_and_alloc_big<A: ArenaAllocator>() {
+        let arena = Arena::new(ARENA_SIZE);
+        let heap = A::with_arena(&arena);
+        let small = Layout::from_size_align(48, 8).unwrap();
+        let blocks: Vec<_> = (0..ARENA_SIZE / 64)
+            .map(|_| unsafe { heap.alloc(small) })
+            .filter(|ptr| !ptr.is_null())
+            .collect();
+        for &ptr in blocks.iter() {
+            unsafe { heap.dealloc(ptr, small) };
+        }
+
+        let big = Layout::from_size_align(ARENA_SIZE / 2, 8).unwrap();
+        assert!(unsafe { heap.alloc(big) }.is_null(), "{} did not fragment", A::NAME);
+        assert!(heap.reclaim() > 0);
+        let ptr = unsafe { heap.alloc(big) };
+        assert!(!ptr.is_null(), "{} did not reclaim: {:?}", A::NAME, heap.heap_stats());
+        unsafe { heap.dealloc(ptr, big) };
+    }
+
+    fill_free_and_alloc_big::<Locked<PoolAllocator>>();
+    fill_free_and_alloc_big::<Locked<BlockAllocator>>();
+}
+
+#[test]
+fn stats_count_allocations() {
+    fn count<A: ArenaAllocator>() {
+        let arena = Arena::new(ARENA_SIZE);
+        let heap = A::with_arena(&arena);
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let big = Layout::from_size_align(4096, 8).unwrap();
+        let huge = Layout::from_size_align(ARENA_SIZE * 2, 8).unwrap();
+
+        let a = unsafe { heap.alloc(small) };
+        let b = unsafe { heap.alloc(big) };
+        assert!(unsafe { heap.alloc(huge) }.is_null());
+        let stats = heap.heap_stats();
+        assert_eq!(stats.heap_size, ARENA_SIZE, "{}", A::NAME);
+        assert_eq!((stats.allocations, stats.deallocations, stats.failed_allocations), (2, 0, 1),
+                   "{}", A::NAME);
+        assert!(stats.bytes_in_use >= small.size() + big.size(), "{}", A::NAME);
+        // 24 bytes fall in the 32 byte class, 4096 bytes in the last one
+        assert_eq!(stats.size_classes[2], 1, "{}", A::NAME);
+        assert_eq!(stats.size_classes[stats.size_classes.len() - 1], 1, "{}", A::NAME);
+
+        let peak = stats.bytes_in_use;
+        unsafe {
+            heap.dealloc(a, small);
+            heap.dealloc(b, big);
+        }
+        let stats = heap.heap_stats();
+        assert_eq!((stats.allocations, stats.deallocations), (2, 2), "{}", A::NAME);
+        assert_eq!(stats.bytes_in_use, 0, "{}", A::NAME);
+        assert_eq!(stats.peak_bytes_in_use, peak, "{}", A::NAME);
+    }
+
+    count::<Locked<BumpAllocator>>();
+    count::<Locked<PoolAllocator>>();
+    count::<Locked<BlockAllocator>>();
+    count::<Locked<BuddyAllocator>>();
+}
@@ -0,0 +1,113 @@
+use super::{GenericAddress, Sdt};
+use x86_64::PhysAddr;
+
+// the fields we use, ACPI 1.0 tables end before the extended ones
+const DSDT: usize = 40;
+const SCI_INTERRUPT: usize = 46;
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_EVENT_BLOCK: usize = 56;
+const PM1B_EVENT_BLOCK: usize = 60;
+const PM1A_CONTROL_BLOCK: usize = 64;
+const PM1B_CONTROL_BLOCK: usize = 68;
+const PM_TIMER_BLOCK: usize = 76;
+const PM1_EVENT_LENGTH: usize = 88;
+const CENTURY: usize = 108;
+const BOOT_ARCH: usize = 109;
+const FLAGS: usize = 112;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+const X_PM1A_EVENT_BLOCK: usize = 148;
+const X_PM1B_EVENT_BLOCK: usize = 160;
+const X_PM1A_CONTROL_BLOCK: usize = 172;
+const X_PM1B_CONTROL_BLOCK: usize = 184;
+const X_PM_TIMER_BLOCK: usize = 208;
+
+const FLAG_TIMER_32BIT: u32 = 1 << 8;
+const FLAG_RESET_SUPPORTED: u32 = 1 << 10;
+
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_NO_CMOS_RTC: u16 = 1 << 5;
+
+/// the fixed hardware of the ACPI power management model
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    /// port to write `acpi_enable` to, 0 when the machine is always in ACPI mode
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm_timer_32bit: bool,
+    /// CMOS register of the century, 0 when there is none
+    pub century: u8,
+    pub boot_arch: u16,
+    pub flags: u32,
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    pub fn parse(sdt: Sdt) -> Fadt {
+        let u32_at = |offset| sdt.read::<u32>(offset).unwrap_or(0);
+        let address_at = |offset| sdt.read::<[u8; 12]>(offset).map(GenericAddress::parse);
+        // the extended I/O port wins when it is there
+        let port = |legacy, extended| match address_at(extended) {
+            Some(address) if !address.is_null() && address.space == GenericAddress::SPACE_IO =>
+                address.address as u32,
+            _ => u32_at(legacy),
+        };
+
+        let flags = u32_at(FLAGS);
+        let dsdt = match sdt.read::<u64>(X_DSDT) {
+            Some(address) if address != 0 => address,
+            _ => u32_at(DSDT) as u64,
+        };
+        let reset = match address_at(RESET_REGISTER) {
+            Some(register) if flags & FLAG_RESET_SUPPORTED != 0 && !register.is_null() =>
+                Some((register, sdt.read::<u8>(RESET_VALUE).unwrap_or(0))),
+            _ => None,
+        };
+
+        Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: sdt.read::<u16>(SCI_INTERRUPT).unwrap_or(0),
+            smi_command: u32_at(SMI_COMMAND),
+            acpi_enable: sdt.read::<u8>(ACPI_ENABLE).unwrap_or(0),
+            acpi_disable: sdt.read::<u8>(ACPI_DISABLE).unwrap_or(0),
+            pm1a_event_block: port(PM1A_EVENT_BLOCK, X_PM1A_EVENT_BLOCK),
+            pm1b_event_block: port(PM1B_EVENT_BLOCK, X_PM1B_EVENT_BLOCK),
+            pm1_event_length: sdt.read::<u8>(PM1_EVENT_LENGTH).unwrap_or(0),
+            pm1a_control_block: port(PM1A_CONTROL_BLOCK, X_PM1A_CONTROL_BLOCK),
+            pm1b_control_block: port(PM1B_CONTROL_BLOCK, X_PM1B_CONTROL_BLOCK),
+            pm_timer_block: port(PM_TIMER_BLOCK, X_PM_TIMER_BLOCK),
+            pm_timer_32bit: flags & FLAG_TIMER_32BIT != 0,
+            century: sdt.read::<u8>(CENTURY).unwrap_or(0),
+            // only meaningful since ACPI 2.0
+            boot_arch: if sdt.revision() >= 2 { sdt.read::<u16>(BOOT_ARCH).unwrap_or(0) } else { 0 },
+            flags,
+            reset,
+        }
+    }
+
+    // firmware older than ACPI 2.0 does not say, and is assumed to be a PC
+    pub fn has_legacy_devices(&self) -> bool {
+        self.boot_arch == 0 || self.boot_arch & BOOT_ARCH_LEGACY_DEVICES != 0
+    }
+
+    pub fn has_8042(&self) -> bool {
+        self.boot_arch == 0 || self.boot_arch & BOOT_ARCH_8042 != 0
+    }
+
+    pub fn has_cmos_rtc(&self) -> bool {
+        self.boot_arch & BOOT_ARCH_NO_CMOS_RTC == 0
+    }
+}
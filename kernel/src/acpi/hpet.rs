@@ -0,0 +1,40 @@
+use super::{GenericAddress, Sdt};
+use x86_64::PhysAddr;
+
+const BLOCK_ID: usize = 36;
+const BASE_ADDRESS: usize = 40;
+const NUMBER: usize = 52;
+const MINIMUM_TICK: usize = 53;
+
+/// the event timer block described by the HPET table
+#[derive(Debug, Clone)]
+pub struct Hpet {
+    pub address: PhysAddr,
+    pub number: u8,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub vendor: u16,
+    /// the shortest period in periodic mode, in counter ticks
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// None when the registers are not memory mapped
+    pub fn parse(sdt: Sdt) -> Option<Hpet> {
+        let block_id = sdt.read::<u32>(BLOCK_ID)?;
+        let base = GenericAddress::parse(sdt.read::<[u8; 12]>(BASE_ADDRESS)?);
+        if base.space != GenericAddress::SPACE_MEMORY || base.is_null() {
+            return None;
+        }
+        Some(Hpet {
+            address: PhysAddr::new(base.address),
+            number: sdt.read::<u8>(NUMBER).unwrap_or(0),
+            comparators: (block_id >> 8 & 0x1f) as u8 + 1,
+            counter_64bit: block_id & 1 << 13 != 0,
+            legacy_replacement: block_id & 1 << 15 != 0,
+            vendor: (block_id >> 16) as u16,
+            minimum_tick: sdt.read::<u16>(MINIMUM_TICK).unwrap_or(0),
+        })
+    }
+}
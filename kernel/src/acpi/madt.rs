@@ -0,0 +1,138 @@
+use super::Sdt;
+use crate::driver::{
+    apic::{InterruptOverride, IoApicInfo, LocalNmi, Topology},
+    ioapic::Polarity,
+};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    /// disabled now, but may be brought online later
+    pub online_capable: bool,
+}
+
+/// the local APIC pin wired to NMI
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// None for every processor
+    pub acpi_id: Option<u8>,
+    pub lint: u8,
+    pub polarity: Polarity,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    /// there are 8259 PICs to disable
+    pub pcat_compat: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+// MPS INTI flags, 0 means the bus default, which for ISA is active high and edge
+fn polarity(flags: u16) -> Polarity {
+    Polarity {
+        active_low: flags & 0b11 == 0b11,
+        level_triggered: flags >> 2 & 0b11 == 0b11,
+    }
+}
+
+impl Madt {
+    pub fn parse(sdt: Sdt) -> Madt {
+        let mut madt = Madt {
+            local_apic: PhysAddr::new(sdt.read::<u32>(36).unwrap_or(0) as u64),
+            pcat_compat: sdt.read::<u32>(40).unwrap_or(0) & FLAG_PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut offset = 44;
+        while let (Some(kind), Some(length)) = (sdt.read::<u8>(offset), sdt.read::<u8>(offset + 1)) {
+            // a broken entry would loop forever
+            if length < 2 {
+                break;
+            }
+            let field = |at: usize| offset + at;
+            match kind {
+                ENTRY_LOCAL_APIC => {
+                    let flags = sdt.read::<u32>(field(4)).unwrap_or(0);
+                    madt.processors.push(Processor {
+                        acpi_id: sdt.read::<u8>(field(2)).unwrap_or(0) as u32,
+                        apic_id: sdt.read::<u8>(field(3)).unwrap_or(0) as u32,
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_LOCAL_X2APIC => {
+                    let flags = sdt.read::<u32>(field(8)).unwrap_or(0);
+                    madt.processors.push(Processor {
+                        acpi_id: sdt.read::<u32>(field(12)).unwrap_or(0),
+                        apic_id: sdt.read::<u32>(field(4)).unwrap_or(0),
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_IO_APIC => madt.io_apics.push(IoApicInfo {
+                    address: PhysAddr::new(sdt.read::<u32>(field(4)).unwrap_or(0) as u64),
+                    gsi_base: sdt.read::<u32>(field(8)).unwrap_or(0),
+                }),
+                ENTRY_OVERRIDE => madt.overrides.push(InterruptOverride {
+                    isa_irq: sdt.read::<u8>(field(3)).unwrap_or(0),
+                    gsi: sdt.read::<u32>(field(4)).unwrap_or(0),
+                    polarity: polarity(sdt.read::<u16>(field(8)).unwrap_or(0)),
+                }),
+                ENTRY_LOCAL_APIC_NMI => {
+                    let acpi_id = sdt.read::<u8>(field(2)).unwrap_or(0xff);
+                    madt.nmis.push(LocalApicNmi {
+                        acpi_id: if acpi_id == 0xff { None } else { Some(acpi_id) },
+                        polarity: polarity(sdt.read::<u16>(field(3)).unwrap_or(0)),
+                        lint: sdt.read::<u8>(field(5)).unwrap_or(0),
+                    });
+                }
+                ENTRY_LOCAL_APIC_ADDRESS => if let Some(address) = sdt.read::<u64>(field(4)) {
+                    madt.local_apic = PhysAddr::new(address);
+                },
+                _ => {}
+            }
+            offset += length as usize;
+        }
+        madt
+    }
+
+    /// what the APIC driver needs to route interrupts
+    pub fn topology(&self) -> Topology {
+        Topology {
+            local_apic: self.local_apic,
+            io_apics: self.io_apics.clone(),
+            overrides: self.overrides.clone(),
+            // the driver knows processors by their APIC id
+            nmis: self.nmis.iter().filter_map(|nmi| {
+                let apic_id = match nmi.acpi_id {
+                    Some(acpi_id) => Some(self.processors.iter()
+                        .find(|p| p.acpi_id == acpi_id as u32)?.apic_id),
+                    None => None,
+                };
+                Some(LocalNmi { apic_id, lint: nmi.lint, polarity: nmi.polarity })
+            }).collect(),
+        }
+    }
+}
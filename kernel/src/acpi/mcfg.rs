@@ -0,0 +1,47 @@
+use super::Sdt;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+// entries follow the header and 8 reserved bytes
+const ENTRIES: usize = 44;
+const ENTRY_LENGTH: usize = 16;
+
+/// memory mapped PCI configuration space of one segment
+#[derive(Debug, Clone, Copy)]
+pub struct PciConfigRegion {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub regions: Vec<PciConfigRegion>,
+}
+
+impl Mcfg {
+    pub fn parse(sdt: Sdt) -> Mcfg {
+        let count = sdt.bytes().len().saturating_sub(ENTRIES) / ENTRY_LENGTH;
+        let regions = (0..count)
+            .map(|i| ENTRIES + i * ENTRY_LENGTH)
+            .map(|entry| PciConfigRegion {
+                base: PhysAddr::new(sdt.read::<u64>(entry).unwrap_or(0)),
+                segment: sdt.read::<u16>(entry + 8).unwrap_or(0),
+                start_bus: sdt.read::<u8>(entry + 10).unwrap_or(0),
+                end_bus: sdt.read::<u8>(entry + 11).unwrap_or(0),
+            })
+            .collect();
+        Mcfg { regions }
+    }
+
+    /// where the 4 KiB configuration space of a function starts
+    pub fn address(&self, segment: u16, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        let region = self.regions.iter()
+            .find(|r| r.segment == segment && r.start_bus <= bus && bus <= r.end_bus)?;
+        let offset = ((bus - region.start_bus) as u64) << 20
+            | (device as u64) << 15
+            | (function as u64) << 12;
+        Some(region.base + offset)
+    }
+}
@@ -0,0 +1,293 @@
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+pub mod mcfg;
+
+use crate::{klogln, memory::phys_to_virt};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{fmt, mem, ops::Range, ptr, slice, str};
+use x86_64::PhysAddr;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::Madt;
+pub use mcfg::Mcfg;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// the checksum of ACPI 1.0 covers only the first 20 bytes
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+const SDT_HEADER_LENGTH: usize = 36;
+
+// the real mode segment of the EBDA is kept in the BIOS data area
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+const BIOS_AREA: Range<u64> = 0xe_0000..0x10_0000;
+
+/// the four letters naming a table
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl Signature {
+    pub const MADT: Signature = Signature(*b"APIC");
+    pub const FADT: Signature = Signature(*b"FACP");
+    pub const HPET: Signature = Signature(*b"HPET");
+    pub const MCFG: Signature = Signature(*b"MCFG");
+    pub const DSDT: Signature = Signature(*b"DSDT");
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(str::from_utf8(&self.0).unwrap_or("????"))
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    BadRsdp,
+    BadChecksum(Signature),
+    BadSignature { expected: Signature, found: Signature },
+    TooShort(Signature),
+    AlreadyInitialized,
+}
+
+/// where a register lives, as the tables describe it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SPACE_MEMORY: u8 = 0;
+    pub const SPACE_IO: u8 = 1;
+    pub const SPACE_PCI_CONFIG: u8 = 2;
+
+    fn parse(bytes: [u8; 12]) -> Self {
+        let mut address = [0; 8];
+        address.copy_from_slice(&bytes[4..]);
+        GenericAddress {
+            space: bytes[0],
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: u64::from_le_bytes(address),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.address == 0
+    }
+}
+
+pub(crate) fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+// firmware memory is covered by the physical memory mapping
+pub(crate) unsafe fn phys_bytes(address: PhysAddr, length: usize) -> &'static [u8] {
+    slice::from_raw_parts(phys_to_virt(address).as_ptr(), length)
+}
+
+/// a validated system description table
+#[derive(Clone, Copy)]
+pub struct Sdt {
+    pub address: PhysAddr,
+    pub signature: Signature,
+    bytes: &'static [u8],
+}
+
+impl Sdt {
+    /// the table at `address`, if its checksum holds
+    pub fn at(address: PhysAddr) -> Result<Sdt, AcpiError> {
+        let header = unsafe { phys_bytes(address, SDT_HEADER_LENGTH) };
+        let signature = Signature([header[0], header[1], header[2], header[3]]);
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if length < SDT_HEADER_LENGTH {
+            return Err(AcpiError::TooShort(signature));
+        }
+        let bytes = unsafe { phys_bytes(address, length) };
+        if !checksum(bytes) {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+        Ok(Sdt { address, signature, bytes })
+    }
+
+    fn expect(address: PhysAddr, expected: Signature) -> Result<Sdt, AcpiError> {
+        let sdt = Sdt::at(address)?;
+        if sdt.signature != expected {
+            return Err(AcpiError::BadSignature { expected, found: sdt.signature });
+        }
+        Ok(sdt)
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    /// the whole table, header included
+    pub fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    /// the table after the header
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes[SDT_HEADER_LENGTH..]
+    }
+
+    /// a field at `offset` from the start of the table, None past its end.
+    /// Older revisions of a table are shorter.
+    pub fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + mem::size_of::<T>() > self.bytes.len() {
+            return None;
+        }
+        Some(unsafe { ptr::read_unaligned(self.bytes[offset..].as_ptr() as *const T) })
+    }
+}
+
+impl fmt::Debug for Sdt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#x} ({} bytes)", self.signature, self.address.as_u64(), self.bytes.len())
+    }
+}
+
+struct Rsdp {
+    revision: u8,
+    oem_id: [u8; 6],
+    rsdt: u32,
+    xsdt: Option<u64>,
+}
+
+fn parse_rsdp(address: PhysAddr) -> Option<Rsdp> {
+    let bytes = unsafe { phys_bytes(address, RSDP_V1_LENGTH) };
+    if &bytes[..8] != RSDP_SIGNATURE || !checksum(bytes) {
+        return None;
+    }
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&bytes[9..15]);
+    let revision = bytes[15];
+    let rsdt = u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]);
+    if revision < 2 {
+        return Some(Rsdp { revision, oem_id, rsdt, xsdt: None });
+    }
+
+    // ACPI 2.0 extends the structure and checksums it again
+    let header = unsafe { phys_bytes(address, RSDP_V2_LENGTH) };
+    let length = u32::from_le_bytes([header[20], header[21], header[22], header[23]]) as usize;
+    let bytes = unsafe { phys_bytes(address, length.max(RSDP_V2_LENGTH)) };
+    if !checksum(bytes) {
+        return None;
+    }
+    let mut xsdt = [0; 8];
+    xsdt.copy_from_slice(&bytes[24..32]);
+    Some(Rsdp { revision, oem_id, rsdt, xsdt: Some(u64::from_le_bytes(xsdt)) })
+}
+
+// the RSDP sits on a 16 byte boundary in the first KiB of the EBDA,
+// or in the BIOS area below 1 MiB
+fn scan_for_rsdp() -> Option<PhysAddr> {
+    let ebda = unsafe {
+        ptr::read_unaligned(phys_to_virt(PhysAddr::new(EBDA_SEGMENT_POINTER)).as_ptr::<u16>())
+    } as u64 * 16;
+    let ebda = if ebda == 0 { 0..0 } else { ebda..ebda + 1024 };
+    ebda.step_by(16)
+        .chain(BIOS_AREA.step_by(16))
+        .map(PhysAddr::new)
+        .find(|&address| parse_rsdp(address).is_some())
+}
+
+pub struct Acpi {
+    pub revision: u8,
+    oem_id: [u8; 6],
+    pub tables: Vec<Sdt>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    pub fn find(&self, signature: Signature) -> Option<Sdt> {
+        self.tables.iter().copied().find(|sdt| sdt.signature == signature)
+    }
+
+    pub fn oem_id(&self) -> &str {
+        str::from_utf8(&self.oem_id).unwrap_or("").trim_end()
+    }
+}
+
+static ACPI: OnceCell<Acpi> = OnceCell::uninit();
+
+/// the tables found by `init`
+pub fn tables() -> Option<&'static Acpi> {
+    ACPI.try_get().ok()
+}
+
+/// validate the tables reachable from the RSDP at `rsdp`, or from one
+/// found in the BIOS area when the boot loader did not pass it.
+/// Tables that fail their checksum are left out.
+pub fn init(rsdp: Option<PhysAddr>) -> Result<&'static Acpi, AcpiError> {
+    let rsdp = match rsdp.or_else(scan_for_rsdp) {
+        Some(address) => parse_rsdp(address).ok_or(AcpiError::BadRsdp)?,
+        None => return Err(AcpiError::NoRsdp),
+    };
+
+    let addresses: Vec<u64> = match rsdp.xsdt {
+        Some(xsdt) => Sdt::expect(PhysAddr::new(xsdt), Signature(*b"XSDT"))?
+            .data().chunks_exact(8)
+            .map(|entry| {
+                let mut address = [0; 8];
+                address.copy_from_slice(entry);
+                u64::from_le_bytes(address)
+            })
+            .collect(),
+        None => Sdt::expect(PhysAddr::new(rsdp.rsdt as u64), Signature(*b"RSDT"))?
+            .data().chunks_exact(4)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as u64)
+            .collect(),
+    };
+
+    let mut tables = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        match Sdt::at(PhysAddr::new(address)) {
+            Ok(sdt) => tables.push(sdt),
+            Err(e) => klogln!("acpi: skipping table at {:#x}: {:?}", address, e),
+        }
+    }
+
+    let mut acpi = Acpi {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        tables,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+    };
+    acpi.madt = acpi.find(Signature::MADT).map(Madt::parse);
+    acpi.fadt = acpi.find(Signature::FADT).map(Fadt::parse);
+    acpi.hpet = acpi.find(Signature::HPET).and_then(Hpet::parse);
+    acpi.mcfg = acpi.find(Signature::MCFG).map(Mcfg::parse);
+
+    ACPI.try_init_once(|| acpi).map_err(|_| AcpiError::AlreadyInitialized)?;
+    Ok(ACPI.try_get().unwrap())
+}
+
+#[test_case]
+fn test_acpi_tables() {
+    let acpi = tables().expect("no ACPI tables");
+    assert!(acpi.tables.iter().all(|sdt| checksum(sdt.bytes())));
+    let madt = acpi.madt.as_ref().expect("no MADT");
+    assert!(madt.processors.iter().any(|cpu| cpu.enabled));
+    assert!(!madt.io_apics.is_empty());
+}
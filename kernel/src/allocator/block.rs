@@ -1,4 +1,4 @@
-use super::{pool::PoolAllocator, Locked};
+use super::{pool::PoolAllocator, Locked, HeapStats, HeapStatistics, Reclaim};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{ptr, mem};
 
@@ -13,6 +13,7 @@ const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 pub struct BlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: PoolAllocator,
+    stats: HeapStats,
 }
 
 impl BlockAllocator {
@@ -20,11 +21,13 @@ impl BlockAllocator {
         Self {
             list_heads: [None; BLOCK_SIZES.len()],
             fallback_allocator: PoolAllocator::new(),
+            stats: HeapStats::new(),
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
+        self.stats.heap_size = heap_size;
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
@@ -38,12 +41,25 @@ impl BlockAllocator {
         let required_block_size = layout.size().max(layout.align());
         BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
     }
+
+    // what a block really takes from the fallback allocator
+    fn block_footprint(index: usize) -> usize {
+        let layout = Layout::from_size_align(BLOCK_SIZES[index], BLOCK_SIZES[index]).unwrap();
+        PoolAllocator::size_align(layout).0
+    }
+
+    fn used_size(layout: &Layout) -> usize {
+        match BlockAllocator::list_index(layout) {
+            Some(index) => BlockAllocator::block_footprint(index),
+            None => PoolAllocator::size_align(*layout).0,
+        }
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match BlockAllocator::list_index(&layout) {
+        let ptr = match BlockAllocator::list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
@@ -59,11 +75,19 @@ unsafe impl GlobalAlloc for Locked<BlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+
+        if ptr.is_null() {
+            allocator.stats.record_failure();
+        } else {
+            allocator.stats.record_alloc(&layout, BlockAllocator::used_size(&layout));
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.stats.record_dealloc(BlockAllocator::used_size(&layout));
         match BlockAllocator::list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
@@ -81,4 +105,42 @@ unsafe impl GlobalAlloc for Locked<BlockAllocator> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+impl HeapStatistics for Locked<BlockAllocator> {
+    fn heap_stats(&self) -> HeapStats {
+        let allocator = self.lock();
+        let mut stats = allocator.stats;
+        let (mut free_bytes, mut largest_free_block) = allocator.fallback_allocator.free_summary();
+        for (index, head) in allocator.list_heads.iter().enumerate() {
+            let mut current = head;
+            while let Some(node) = current {
+                free_bytes += BlockAllocator::block_footprint(index);
+                largest_free_block = largest_free_block.max(BLOCK_SIZES[index]);
+                current = &node.next;
+            }
+        }
+        stats.free_bytes = free_bytes;
+        stats.largest_free_block = largest_free_block;
+        stats
+    }
+}
+impl Reclaim for Locked<BlockAllocator> {
+    // cached blocks only serve their own size, hand them back to the pool
+    fn reclaim(&self) -> usize {
+        let mut allocator = match self.try_lock() {
+            Some(allocator) => allocator,
+            None => return 0,
+        };
+        let mut drained = 0;
+        for index in 0..BLOCK_SIZES.len() {
+            while let Some(node) = allocator.list_heads[index].take() {
+                allocator.list_heads[index] = node.next.take();
+                let layout = Layout::from_size_align(BLOCK_SIZES[index], BLOCK_SIZES[index]).unwrap();
+                allocator.fallback_allocator.deallocate(node as *mut ListNode as *mut u8, layout);
+                drained += BlockAllocator::block_footprint(index);
+            }
+        }
+        drained + allocator.fallback_allocator.coalesce()
+    }
+}
@@ -0,0 +1,194 @@
+use super::{Locked, HeapStats, HeapStatistics, Reclaim};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+const MIN_BLOCK_SIZE: usize = 16;
+// blocks go from 16 bytes up to 16 << (ORDERS - 1) = 64MB
+const ORDERS: usize = 23;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+pub struct BuddyAllocator {
+    heap_start: usize,
+    heap_size: usize,
+    free_lists: [*mut FreeBlock; ORDERS],
+    stats: HeapStats,
+}
+
+// the free lists only point into the heap owned by this allocator
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            heap_start: 0,
+            heap_size: 0,
+            free_lists: [ptr::null_mut(); ORDERS],
+            stats: HeapStats::new(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
+        self.stats.heap_size = heap_size;
+
+        // cut the heap into the largest blocks that are aligned to their size
+        let mut offset = 0;
+        while offset + MIN_BLOCK_SIZE <= heap_size {
+            let mut order = ORDERS - 1;
+            while Self::block_size(order) > heap_size - offset
+                || offset % Self::block_size(order) != 0
+            {
+                order -= 1;
+            }
+            self.push(order, offset);
+            offset += Self::block_size(order);
+        }
+    }
+
+    fn block_size(order: usize) -> usize {
+        MIN_BLOCK_SIZE << order
+    }
+
+    fn order_of(layout: &Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).max(MIN_BLOCK_SIZE).next_power_of_two();
+        let order = (size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+        if order < ORDERS {
+            Some(order)
+        } else {
+            None
+        }
+    }
+
+    unsafe fn push(&mut self, order: usize, offset: usize) {
+        let block = (self.heap_start + offset) as *mut FreeBlock;
+        block.write(FreeBlock { next: self.free_lists[order] });
+        self.free_lists[order] = block;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order];
+        if block.is_null() {
+            return None;
+        }
+        self.free_lists[order] = (*block).next;
+        Some(block as usize - self.heap_start)
+    }
+
+    unsafe fn remove(&mut self, order: usize, offset: usize) -> bool {
+        let target = (self.heap_start + offset) as *mut FreeBlock;
+        let mut link: *mut *mut FreeBlock = &mut self.free_lists[order];
+        while !(*link).is_null() {
+            if *link == target {
+                *link = (*target).next;
+                return true;
+            }
+            link = &mut (**link).next;
+        }
+        false
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if self.heap_size == 0 {
+            return ptr::null_mut();
+        }
+        // blocks are aligned relative to the heap start only
+        let heap_align = 1 << self.heap_start.trailing_zeros();
+        let order = match Self::order_of(&layout) {
+            Some(order) if layout.align() <= heap_align => order,
+            _ => return ptr::null_mut(),
+        };
+
+        unsafe {
+            let mut current = order;
+            let offset = loop {
+                if current == ORDERS {
+                    return ptr::null_mut();
+                }
+                if let Some(offset) = self.pop(current) {
+                    break offset;
+                }
+                current += 1;
+            };
+
+            // give back the upper halves until the block has the wanted size
+            while current > order {
+                current -= 1;
+                self.push(current, offset + Self::block_size(current));
+            }
+            (self.heap_start + offset) as *mut u8
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut order = Self::order_of(&layout).expect("invalid layout for buddy allocator");
+        let mut offset = ptr as usize - self.heap_start;
+
+        while order < ORDERS - 1 {
+            let buddy = offset ^ Self::block_size(order);
+            if buddy + Self::block_size(order) > self.heap_size || !self.remove(order, buddy) {
+                break;
+            }
+            offset = offset.min(buddy);
+            order += 1;
+        }
+        self.push(order, offset);
+    }
+
+    /// total size of the free blocks and the size of the largest one
+    pub fn free_summary(&self) -> (usize, usize) {
+        let mut total = 0;
+        let mut largest = 0;
+        for order in 0..ORDERS {
+            let mut block = self.free_lists[order];
+            while !block.is_null() {
+                total += Self::block_size(order);
+                largest = Self::block_size(order);
+                block = unsafe { (*block).next };
+            }
+        }
+        (total, largest)
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut buddy = self.lock();
+        let ptr = buddy.allocate(layout);
+        if ptr.is_null() {
+            buddy.stats.record_failure();
+        } else {
+            let size = BuddyAllocator::block_size(BuddyAllocator::order_of(&layout).unwrap());
+            buddy.stats.record_alloc(&layout, size);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut buddy = self.lock();
+        buddy.deallocate(ptr, layout);
+        let size = BuddyAllocator::block_size(BuddyAllocator::order_of(&layout).unwrap());
+        buddy.stats.record_dealloc(size);
+    }
+}
+
+impl HeapStatistics for Locked<BuddyAllocator> {
+    fn heap_stats(&self) -> HeapStats {
+        let buddy = self.lock();
+        let mut stats = buddy.stats;
+        let (free_bytes, largest_free_block) = buddy.free_summary();
+        stats.free_bytes = free_bytes;
+        stats.largest_free_block = largest_free_block;
+        stats
+    }
+}
+
+impl Reclaim for Locked<BuddyAllocator> {
+    // buddies are merged as soon as they are freed
+    fn reclaim(&self) -> usize {
+        0
+    }
+}
@@ -1,5 +1,5 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use super::{align_up, Locked};
+use super::{align_up, Locked, HeapStats, HeapStatistics, Reclaim};
 use core::ptr;
 
 pub struct BumpAllocator {
@@ -7,6 +7,7 @@ pub struct BumpAllocator {
     head_end: usize,
     next: usize,
     allocations: usize,
+    stats: HeapStats,
 }
 
 impl BumpAllocator {
@@ -16,6 +17,7 @@ impl BumpAllocator {
             head_end: 0,
             next: 0,
             allocations: 0,
+            stats: HeapStats::new(),
         }
     }
 
@@ -23,6 +25,7 @@ impl BumpAllocator {
         self.heap_start = head_start;
         self.next = head_start;
         self.head_end = head_start + heap_size;
+        self.stats.heap_size = heap_size;
     }
 }
 
@@ -33,12 +36,18 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         let alloc_start = align_up(bump.next, layout.align());
         let alloc_end = match alloc_start.checked_add(layout.size()) {
             Some(end) => end,
-            None => return ptr::null_mut(),
+            None => {
+                bump.stats.record_failure();
+                return ptr::null_mut();
+            }
         };
 
         if alloc_end > bump.head_end {
+            bump.stats.record_failure();
             ptr::null_mut()
         } else {
+            let used = alloc_end - bump.next;
+            bump.stats.record_alloc(&layout, used);
             bump.next = alloc_end;
             bump.allocations += 1;
             alloc_start as *mut u8
@@ -49,8 +58,27 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         let mut bump = self.lock();
 
         bump.allocations -= 1;
+        bump.stats.record_dealloc(0);
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
+            bump.stats.bytes_in_use = 0;
         }
     }
-}
\ No newline at end of file
+}
+
+impl HeapStatistics for Locked<BumpAllocator> {
+    fn heap_stats(&self) -> HeapStats {
+        let bump = self.lock();
+        let mut stats = bump.stats;
+        // only the tail of the heap can be handed out again
+        stats.free_bytes = bump.head_end - bump.next;
+        stats.largest_free_block = stats.free_bytes;
+        stats
+    }
+}
+impl Reclaim for Locked<BumpAllocator> {
+    // memory only comes back once every allocation is gone
+    fn reclaim(&self) -> usize {
+        0
+    }
+}
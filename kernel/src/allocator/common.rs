@@ -0,0 +1,93 @@
+// shared by all heap allocators, this file must not depend on the rest
+// of the kernel as it is also built for the host by alloc-host.
+use alloc::alloc::Layout;
+
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<A>> {
+        self.inner.try_lock()
+    }
+}
+
+pub const SIZE_CLASSES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+// the last class counts everything bigger than the largest size class
+pub const SIZE_CLASS_COUNT: usize = SIZE_CLASSES.len() + 1;
+
+fn size_class(layout: &Layout) -> usize {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&s| s >= size).unwrap_or(SIZE_CLASSES.len())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub failed_allocations: usize,
+    pub size_classes: [usize; SIZE_CLASS_COUNT],
+}
+
+impl HeapStats {
+    pub const fn new() -> Self {
+        HeapStats {
+            heap_size: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            free_bytes: 0,
+            largest_free_block: 0,
+            allocations: 0,
+            deallocations: 0,
+            failed_allocations: 0,
+            size_classes: [0; SIZE_CLASS_COUNT],
+        }
+    }
+
+    pub(super) fn record_alloc(&mut self, layout: &Layout, size: usize) {
+        self.allocations += 1;
+        self.size_classes[size_class(layout)] += 1;
+        self.bytes_in_use += size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+
+    pub(super) fn record_dealloc(&mut self, size: usize) {
+        self.deallocations += 1;
+        self.bytes_in_use -= size;
+    }
+
+    pub(super) fn record_failure(&mut self) {
+        self.failed_allocations += 1;
+    }
+}
+
+/// usage information of a heap allocator
+pub trait HeapStatistics {
+    fn heap_stats(&self) -> HeapStats;
+}
+
+/// give cached or fragmented memory back, so that it can serve any
+/// allocation again. Returns the number of bytes recovered, 0 when the
+/// allocator is busy, as reclaiming may run while any lock is held.
+pub trait Reclaim {
+    fn reclaim(&self) -> usize;
+}
+
+pub(super) fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,254 @@
+use super::{align_up, Reclaim};
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{ops::Deref, ptr};
+
+const RED_ZONE: usize = 32;
+const RED_ZONE_BYTE: u8 = 0xfd;
+const ALLOC_POISON: u8 = 0xcd;
+const FREE_POISON: u8 = 0xdd;
+
+// must be a power of two
+const MAX_RECORDS: usize = 8192;
+const RECENT_FREES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Record {
+    ptr: usize,
+    size: usize,
+    align: usize,
+}
+
+impl Record {
+    const EMPTY: Record = Record { ptr: 0, size: 0, align: 0 };
+}
+
+// open addressing table of every live allocation, keyed by the user pointer
+struct Records {
+    slots: [Record; MAX_RECORDS],
+    len: usize,
+    recent_frees: [usize; RECENT_FREES],
+    recent_next: usize,
+}
+
+impl Records {
+    const fn new() -> Self {
+        Records {
+            slots: [Record::EMPTY; MAX_RECORDS],
+            len: 0,
+            recent_frees: [0; RECENT_FREES],
+            recent_next: 0,
+        }
+    }
+
+    fn slot(ptr: usize) -> usize {
+        (ptr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 51 & (MAX_RECORDS - 1)
+    }
+
+    fn insert(&mut self, record: Record) -> bool {
+        if self.len == MAX_RECORDS - 1 {
+            return false;
+        }
+        let mut index = Self::slot(record.ptr);
+        while self.slots[index].ptr != 0 {
+            index = (index + 1) & (MAX_RECORDS - 1);
+        }
+        self.slots[index] = record;
+        self.len += 1;
+        true
+    }
+
+    fn remove(&mut self, ptr: usize) -> Option<Record> {
+        let mut index = Self::slot(ptr);
+        loop {
+            match self.slots[index].ptr {
+                0 => return None,
+                p if p == ptr => break,
+                _ => index = (index + 1) & (MAX_RECORDS - 1),
+            }
+        }
+        let record = self.slots[index];
+        self.slots[index] = Record::EMPTY;
+        self.len -= 1;
+
+        // shift the rest of the probe chain back into the hole
+        let mut hole = index;
+        let mut next = (hole + 1) & (MAX_RECORDS - 1);
+        while self.slots[next].ptr != 0 {
+            let home = Self::slot(self.slots[next].ptr);
+            if (next.wrapping_sub(home) & (MAX_RECORDS - 1)) >= (next.wrapping_sub(hole) & (MAX_RECORDS - 1)) {
+                self.slots[hole] = self.slots[next];
+                self.slots[next] = Record::EMPTY;
+                hole = next;
+            }
+            next = (next + 1) & (MAX_RECORDS - 1);
+        }
+
+        self.recent_frees[self.recent_next] = ptr;
+        self.recent_next = (self.recent_next + 1) % RECENT_FREES;
+        Some(record)
+    }
+
+    fn recently_freed(&self, ptr: usize) -> bool {
+        self.recent_frees.iter().any(|&p| p == ptr)
+    }
+}
+
+enum HeapError {
+    DoubleFree,
+    InvalidFree,
+    LayoutMismatch(Layout),
+    Overrun(usize),
+    Underrun(usize),
+}
+
+/// wraps a heap allocator with red zones, poisoning and a record of
+/// every live allocation to catch misuse of the heap.
+pub struct DebugHeap<A> {
+    inner: A,
+    records: spin::Mutex<Records>,
+}
+
+impl<A> DebugHeap<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugHeap {
+            inner,
+            records: spin::Mutex::new(Records::new()),
+        }
+    }
+
+    pub fn live_allocations(&self) -> usize {
+        self.records.lock().len
+    }
+
+    fn front_size(align: usize) -> usize {
+        align_up(RED_ZONE, align)
+    }
+
+    fn outer_layout(layout: &Layout) -> Layout {
+        let front = Self::front_size(layout.align());
+        Layout::from_size_align(front + layout.size() + RED_ZONE, layout.align())
+            .expect("debug heap layout overflow")
+    }
+
+    unsafe fn check_red_zones(ptr: usize, record: &Record) -> Result<(), HeapError> {
+        let front = Self::front_size(record.align);
+        let start = ptr - front;
+        for addr in start..ptr {
+            if *(addr as *const u8) != RED_ZONE_BYTE {
+                return Err(HeapError::Underrun(addr));
+            }
+        }
+        let end = ptr + record.size;
+        for addr in end..end + RED_ZONE {
+            if *(addr as *const u8) != RED_ZONE_BYTE {
+                return Err(HeapError::Overrun(addr));
+            }
+        }
+        Ok(())
+    }
+
+    fn report(error: HeapError, ptr: *mut u8, layout: Layout) -> ! {
+        let reason = match error {
+            HeapError::DoubleFree => "double free",
+            HeapError::InvalidFree => "free of a pointer that was never allocated",
+            HeapError::LayoutMismatch(_) => "dealloc with a mismatched layout",
+            HeapError::Overrun(_) => "buffer overrun",
+            HeapError::Underrun(_) => "buffer underrun",
+        };
+        serial_println!("HEAP ERROR: {} at {:p}, freed with {:?}", reason, ptr, layout);
+        match error {
+            HeapError::LayoutMismatch(allocated) =>
+                serial_println!("  allocated with {:?}", allocated),
+            HeapError::Overrun(addr) | HeapError::Underrun(addr) =>
+                serial_println!("  red zone corrupted at {:#x}", addr),
+            _ => (),
+        }
+        panic!("heap corruption: {} at {:p}", reason, ptr);
+    }
+}
+
+impl<A> Deref for DebugHeap<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+impl<A: Reclaim> Reclaim for DebugHeap<A> {
+    fn reclaim(&self) -> usize {
+        self.inner.reclaim()
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut records = self.records.lock();
+        let outer = Self::outer_layout(&layout);
+        let start = self.inner.alloc(outer);
+        if start.is_null() {
+            return start;
+        }
+
+        let ptr = start.add(Self::front_size(layout.align()));
+        let record = Record { ptr: ptr as usize, size: layout.size(), align: layout.align() };
+        if !records.insert(record) {
+            self.inner.dealloc(start, outer);
+            return ptr::null_mut();
+        }
+
+        ptr::write_bytes(start, RED_ZONE_BYTE, outer.size());
+        ptr::write_bytes(ptr, ALLOC_POISON, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut records = self.records.lock();
+        let record = match records.remove(ptr as usize) {
+            Some(record) => record,
+            None => {
+                let error = if records.recently_freed(ptr as usize) {
+                    HeapError::DoubleFree
+                } else {
+                    HeapError::InvalidFree
+                };
+                drop(records);
+                Self::report(error, ptr, layout);
+            }
+        };
+        drop(records);
+
+        if record.size != layout.size() || record.align != layout.align() {
+            let allocated = Layout::from_size_align_unchecked(record.size, record.align);
+            Self::report(HeapError::LayoutMismatch(allocated), ptr, layout);
+        }
+        if let Err(error) = Self::check_red_zones(ptr as usize, &record) {
+            Self::report(error, ptr, layout);
+        }
+
+        let outer = Self::outer_layout(&layout);
+        let start = ptr.sub(Self::front_size(layout.align()));
+        ptr::write_bytes(start, FREE_POISON, outer.size());
+        self.inner.dealloc(start, outer);
+    }
+}
+
+#[test_case]
+fn test_records_remove_keeps_chains() {
+    static RECORDS: spin::Mutex<Records> = spin::Mutex::new(Records::new());
+
+    let mut records = RECORDS.lock();
+    for i in 1..=512 {
+        assert!(records.insert(Record { ptr: i * 16, size: 16, align: 8 }));
+    }
+    for i in (1..=512).step_by(2) {
+        assert_eq!(records.remove(i * 16).map(|r| r.ptr), Some(i * 16));
+    }
+    for i in (2..=512).step_by(2) {
+        assert_eq!(records.remove(i * 16).map(|r| r.ptr), Some(i * 16));
+    }
+    assert!(records.remove(16).is_none());
+    assert!(records.recently_freed(512 * 16));
+    assert_eq!(records.len, 0);
+}
@@ -0,0 +1,105 @@
+use alloc::{
+    alloc::{GlobalAlloc, Layout},
+    boxed::Box,
+    vec::Vec,
+};
+use core::{mem, ops::Deref, ptr::NonNull};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError(pub Layout);
+
+/// retries a failed allocation after running the reclaim hooks,
+/// so that cached memory is used before giving up.
+pub struct Reclaiming<A> {
+    inner: A,
+}
+
+impl<A> Reclaiming<A> {
+    pub const fn new(inner: A) -> Self {
+        Reclaiming { inner }
+    }
+}
+
+impl<A> Deref for Reclaiming<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Reclaiming<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        // the inner lock is released again, so hooks may use the heap allocator
+        if ptr.is_null() && crate::oom::reclaim() > 0 {
+            return self.inner.alloc(layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// allocate `layout` from the kernel heap, without ending up in the
+/// out-of-memory handler when it fails
+pub fn try_alloc(layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    if layout.size() == 0 {
+        return Ok(unsafe { NonNull::new_unchecked(layout.align() as *mut u8) });
+    }
+    NonNull::new(unsafe { alloc::alloc::alloc(layout) }).ok_or(AllocError(layout))
+}
+
+/// like `Box::new`, but reports failure to the caller
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let ptr = try_alloc(Layout::new::<T>())?.as_ptr() as *mut T;
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// like `Vec::with_capacity`, but reports failure to the caller
+pub fn try_vec<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    if capacity == 0 || mem::size_of::<T>() == 0 {
+        return Ok(Vec::new());
+    }
+    let layout = Layout::array::<T>(capacity)
+        .map_err(|_| AllocError(Layout::new::<T>()))?;
+    let ptr = try_alloc(layout)?.as_ptr() as *mut T;
+    Ok(unsafe { Vec::from_raw_parts(ptr, 0, capacity) })
+}
+
+/// grow `vec` so that `additional` more elements fit, leaving it
+/// untouched on failure
+pub fn try_reserve<T>(vec: &mut Vec<T>, additional: usize) -> Result<(), AllocError> {
+    if vec.capacity() - vec.len() >= additional {
+        return Ok(());
+    }
+    let capacity = vec.len().checked_add(additional)
+        .map(|needed| needed.max(vec.capacity() * 2))
+        .ok_or(AllocError(Layout::new::<T>()))?;
+    let mut grown = try_vec(capacity)?;
+    grown.append(vec);
+    *vec = grown;
+    Ok(())
+}
+
+#[test_case]
+fn test_try_alloc_reports_failure() {
+    use super::HEAP_SIZE;
+
+    let huge = Layout::from_size_align(HEAP_SIZE * 2, 8).unwrap();
+    assert_eq!(try_alloc(huge), Err(AllocError(huge)));
+    assert!(try_vec::<u64>(HEAP_SIZE).is_err());
+
+    let boxed = try_box([7u8; 64]).expect("try_box failed");
+    assert_eq!(boxed[63], 7);
+    let mut vec = try_vec::<u32>(4).expect("try_vec failed");
+    vec.extend_from_slice(&[1, 2, 3, 4]);
+    try_reserve(&mut vec, 16).expect("try_reserve failed");
+    assert!(vec.capacity() >= 20);
+    assert_eq!(vec, [1, 2, 3, 4]);
+}
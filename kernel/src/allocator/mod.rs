@@ -1,32 +1,25 @@
 pub mod bump;
 pub mod pool;
 pub mod block;
+pub mod buddy;
+pub mod slab;
+pub mod debug;
+pub mod fallible;
+mod common;
 
 use core::ptr::null_mut;
 use alloc::alloc::{GlobalAlloc, Layout};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, Size4KiB,
     },
     VirtAddr,
 };
-use block::BlockAllocator;
-
-pub struct Locked<A> {
-    inner: spin::Mutex<A>,
-}
-
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: spin::Mutex::new(inner),
-        }
-    }
-
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
-    }
-}
+use crate::serial_println;
+use common::align_up;
+pub use common::{Locked, HeapStats, HeapStatistics, Reclaim, SIZE_CLASSES, SIZE_CLASS_COUNT};
+pub use fallible::{try_alloc, try_box, try_vec, try_reserve, AllocError};
+use fallible::Reclaiming;
 
 pub struct DummyAllocator;
 
@@ -40,16 +33,50 @@ unsafe impl GlobalAlloc for DummyAllocator {
     }
 }
 
+#[cfg(any(
+    all(feature = "heap-bump", any(feature = "heap-pool", feature = "heap-block", feature = "heap-buddy")),
+    all(feature = "heap-pool", any(feature = "heap-block", feature = "heap-buddy")),
+    all(feature = "heap-block", feature = "heap-buddy"),
+))]
+compile_error!("only one of the heap-* features can be enabled");
+
+#[cfg(not(any(feature = "heap-bump", feature = "heap-pool",
+              feature = "heap-block", feature = "heap-buddy")))]
+compile_error!("one of the heap-* features must be enabled");
+
+#[cfg(feature = "heap-bump")]
+pub type HeapBackend = bump::BumpAllocator;
+#[cfg(feature = "heap-bump")]
+pub const BACKEND: &str = "bump";
+
+#[cfg(feature = "heap-pool")]
+pub type HeapBackend = pool::PoolAllocator;
+#[cfg(feature = "heap-pool")]
+pub const BACKEND: &str = "pool";
+
+#[cfg(feature = "heap-block")]
+pub type HeapBackend = block::BlockAllocator;
+#[cfg(feature = "heap-block")]
+pub const BACKEND: &str = "block";
+
+#[cfg(feature = "heap-buddy")]
+pub type HeapBackend = buddy::BuddyAllocator;
+#[cfg(feature = "heap-buddy")]
+pub const BACKEND: &str = "buddy";
+
+#[cfg(not(feature = "debug-heap"))]
+#[global_allocator]
+static ALLOCATOR: Reclaiming<Locked<HeapBackend>> =
+    Reclaiming::new(Locked::new(HeapBackend::new()));
+
+#[cfg(feature = "debug-heap")]
 #[global_allocator]
-static ALLOCATOR: Locked<BlockAllocator> = Locked::new(BlockAllocator::new());
+static ALLOCATOR: Reclaiming<debug::DebugHeap<Locked<HeapBackend>>> =
+    Reclaiming::new(debug::DebugHeap::new(Locked::new(HeapBackend::new())));
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 1024 * 1024; // The heap size is 1MB
 
-fn align_up(addr: usize, align: usize) -> usize {
-    (addr + align - 1) & !(align - 1)
-}
-
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
@@ -65,7 +92,7 @@ pub fn init_heap(
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = crate::memory::data_page_flags();
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush()
         }
@@ -74,6 +101,49 @@ pub fn init_heap(
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
+    crate::oom::register_reclaim_hook("heap", reclaim).expect("no slot for the heap reclaim hook");
 
     Ok(())
+}
+
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.heap_stats()
+}
+
+/// reclaim hook of the kernel heap, gives cached blocks back
+pub fn reclaim() -> usize {
+    ALLOCATOR.reclaim()
+}
+
+pub fn print_heap_report() {
+    let stats = heap_stats();
+
+    serial_println!("heap report: {} bytes at {:#x} ({} allocator)",
+                    stats.heap_size, HEAP_START, BACKEND);
+    serial_println!("  in use: {} bytes (peak {} bytes)",
+                    stats.bytes_in_use, stats.peak_bytes_in_use);
+    serial_println!("  free: {} bytes, largest free block {} bytes",
+                    stats.free_bytes, stats.largest_free_block);
+    serial_println!("  allocations: {}, deallocations: {}, failed: {}",
+                    stats.allocations, stats.deallocations, stats.failed_allocations);
+    for (index, count) in stats.size_classes.iter().enumerate() {
+        match SIZE_CLASSES.get(index) {
+            Some(size) => serial_println!("  <= {:<6} {}", size, count),
+            None => serial_println!("  >  {:<6} {}", SIZE_CLASSES[SIZE_CLASSES.len() - 1], count),
+        }
+    }
+}
+
+/// explain why `layout` could not be allocated
+pub fn failure_reason(layout: &Layout) -> &'static str {
+    let stats = heap_stats();
+    if stats.heap_size == 0 {
+        "the heap is not initialized yet, use memory::memblock"
+    } else if stats.free_bytes < layout.size() {
+        "heap exhausted"
+    } else if stats.largest_free_block < layout.size() {
+        "heap fragmented"
+    } else {
+        "no free block satisfies the alignment"
+    }
 }
\ No newline at end of file
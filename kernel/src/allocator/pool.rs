@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, Locked, HeapStats, HeapStatistics, Reclaim};
 use core::{mem, ptr};
 use alloc::alloc::{Layout, GlobalAlloc};
 
@@ -26,17 +26,33 @@ impl ListNode {
 
 pub struct PoolAllocator {
     head: ListNode,
+    stats: HeapStats,
 }
 
 impl PoolAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            stats: HeapStats::new(),
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.stats.heap_size = heap_size;
+    }
+
+    /// total size of the free regions and the size of the largest one
+    pub fn free_summary(&self) -> (usize, usize) {
+        let mut total = 0;
+        let mut largest = 0;
+        let mut current = &self.head.next;
+        while let Some(region) = current {
+            total += region.size;
+            largest = largest.max(region.size);
+            current = &region.next;
+        }
+        (total, largest)
     }
 
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
@@ -71,7 +87,13 @@ impl PoolAllocator {
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
         -> Result<usize, ()>
     {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        // the gap in front of an aligned allocation goes back to the free list,
+        // so it must be able to hold a node.
+        let front_size = alloc_start - region.start_addr();
+        if front_size > 0 && front_size < mem::size_of::<ListNode>() {
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -87,7 +109,7 @@ impl PoolAllocator {
         Ok(alloc_start)
     }
 
-    fn size_align(layout: Layout) -> (usize, usize) {
+    pub(super) fn size_align(layout: Layout) -> (usize, usize) {
         let layout = layout
             .align_to(mem::align_of::<ListNode>())
             .expect("adjusting alignment failed")
@@ -103,12 +125,18 @@ impl PoolAllocator {
 
         if let Some((region, alloc_start)) = self.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let region_start = region.start_addr();
             let excess_size = region.end_addr() - alloc_end;
             if excess_size > 0 {
                 unsafe {
                     self.add_free_region(alloc_end, excess_size);
                 }
             }
+            if alloc_start > region_start {
+                unsafe {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+            }
             Ok(alloc_start as *mut u8)
         } else {
             Err(())
@@ -123,18 +151,89 @@ impl PoolAllocator {
         }
     }
 
-    // TODO: Add a merging operation.
+    /// merge adjacent free regions, returns the bytes that became part
+    /// of a bigger region
+    pub fn coalesce(&mut self) -> usize {
+        let mut merged = 0;
+        let mut pending = self.head.next.take();
+        // rebuild the list sorted by address, merging neighbours on insertion
+        while let Some(region) = pending {
+            pending = region.next.take();
+            let (addr, size) = (region.start_addr(), region.size);
+
+            let mut is_head = true;
+            let mut current = &mut self.head;
+            while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+                current = current.next.as_mut().unwrap();
+                is_head = false;
+            }
+
+            if !is_head && current.end_addr() == addr {
+                current.size += size;
+                merged += size;
+                if current.next.as_ref().map_or(false, |next| next.start_addr() == current.end_addr()) {
+                    let next = current.next.take().unwrap();
+                    current.size += next.size;
+                    merged += next.size;
+                    current.next = next.next.take();
+                }
+                continue;
+            }
+
+            let mut node = ListNode::new(size);
+            node.next = current.next.take();
+            if node.next.as_ref().map_or(false, |next| next.start_addr() == addr + size) {
+                let next = node.next.take().unwrap();
+                node.size += next.size;
+                merged += next.size;
+                node.next = next.next.take();
+            }
+            let node_ptr = addr as *mut ListNode;
+            unsafe {
+                node_ptr.write(node);
+                current.next = Some(&mut *node_ptr);
+            }
+        }
+        merged
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<PoolAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        match self.lock().alloc_first(layout) {
-            Ok(ptr) => ptr,
-            Err(_) => ptr::null_mut(),
+        let mut pool = self.lock();
+        match pool.alloc_first(layout) {
+            Ok(ptr) => {
+                let (size, _) = PoolAllocator::size_align(layout);
+                pool.stats.record_alloc(&layout, size);
+                ptr
+            }
+            Err(_) => {
+                pool.stats.record_failure();
+                ptr::null_mut()
+            }
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.lock().deallocate(ptr, layout);
+        let mut pool = self.lock();
+        pool.deallocate(ptr, layout);
+        let (size, _) = PoolAllocator::size_align(layout);
+        pool.stats.record_dealloc(size);
     }
-}
\ No newline at end of file
+}
+
+impl HeapStatistics for Locked<PoolAllocator> {
+    fn heap_stats(&self) -> HeapStats {
+        let pool = self.lock();
+        let mut stats = pool.stats;
+        let (free_bytes, largest_free_block) = pool.free_summary();
+        stats.free_bytes = free_bytes;
+        stats.largest_free_block = largest_free_block;
+        stats
+    }
+}
+impl Reclaim for Locked<PoolAllocator> {
+    fn reclaim(&self) -> usize {
+        self.try_lock().map_or(0, |mut pool| pool.coalesce())
+    }
+}
@@ -0,0 +1,342 @@
+use super::{align_up, Locked};
+use crate::memory::{data_page_flags, PAGE_ALLOCATOR, MAPPER};
+use crate::serial_println;
+use alloc::{boxed::Box, vec::Vec};
+use core::{marker::PhantomData, mem, ptr::{self, NonNull}};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page},
+    VirtAddr,
+};
+
+pub const SLAB_START: usize = 0x_5555_0000_0000;
+pub const SLAB_AREA_SIZE: usize = 64 * 1024 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+// a slab holds at least this many objects
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// lives at the start of every slab, objects follow it
+struct SlabHeader {
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    pub objects_in_use: usize,
+    pub objects_total: usize,
+    pub slabs: usize,
+    pub pages: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failed: usize,
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    object_align: usize,
+    constructor: Option<fn(*mut u8)>,
+    slab_pages: usize,
+    first_object: usize,
+    objects_per_slab: usize,
+    slabs: *mut SlabHeader,
+    stats: SlabStats,
+}
+
+// the raw pointers only point into slab pages owned by this cache
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize,
+                     constructor: Option<fn(*mut u8)>) -> Self
+    {
+        let object_align = if align > mem::align_of::<FreeObject>() {
+            align
+        } else {
+            mem::align_of::<FreeObject>()
+        };
+        let object_size = {
+            let size = if size > mem::size_of::<FreeObject>() {
+                size
+            } else {
+                mem::size_of::<FreeObject>()
+            };
+            (size + object_align - 1) & !(object_align - 1)
+        };
+        let first_object = (mem::size_of::<SlabHeader>() + object_align - 1) & !(object_align - 1);
+
+        // slabs are a power of two pages so that the owning slab
+        // of an object is found by masking its address
+        let mut slab_pages = 1;
+        while slab_pages * PAGE_SIZE < first_object + MIN_OBJECTS_PER_SLAB * object_size {
+            slab_pages *= 2;
+        }
+        let objects_per_slab = (slab_pages * PAGE_SIZE - first_object) / object_size;
+
+        SlabCache {
+            name,
+            object_size,
+            object_align,
+            constructor,
+            slab_pages,
+            first_object,
+            objects_per_slab,
+            slabs: ptr::null_mut(),
+            stats: SlabStats {
+                objects_in_use: 0,
+                objects_total: 0,
+                slabs: 0,
+                pages: 0,
+                allocations: 0,
+                frees: 0,
+                failed: 0,
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn object_align(&self) -> usize {
+        self.object_align
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+
+    fn slab_bytes(&self) -> usize {
+        self.slab_pages * PAGE_SIZE
+    }
+
+    pub fn alloc(&mut self) -> *mut u8 {
+        let mut slab = self.slabs;
+        unsafe {
+            while !slab.is_null() && (*slab).free.is_null() {
+                slab = (*slab).next;
+            }
+        }
+
+        if slab.is_null() {
+            slab = match self.grow() {
+                Some(slab) => slab,
+                None => {
+                    self.stats.failed += 1;
+                    return ptr::null_mut();
+                }
+            };
+        }
+
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            self.stats.objects_in_use += 1;
+            self.stats.allocations += 1;
+
+            let object = object as *mut u8;
+            if let Some(constructor) = self.constructor {
+                constructor(object);
+            }
+            object
+        }
+    }
+
+    pub unsafe fn free(&mut self, ptr: *mut u8) {
+        let slab = (ptr as usize & !(self.slab_bytes() - 1)) as *mut SlabHeader;
+        debug_assert!(self.owns(slab), "object freed to the wrong slab cache");
+        debug_assert_eq!((ptr as usize - slab as usize - self.first_object) % self.object_size, 0);
+
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject { next: (*slab).free });
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        self.stats.objects_in_use -= 1;
+        self.stats.frees += 1;
+    }
+
+    fn owns(&self, target: *mut SlabHeader) -> bool {
+        let mut slab = self.slabs;
+        while !slab.is_null() {
+            if slab == target {
+                return true;
+            }
+            slab = unsafe { (*slab).next };
+        }
+        false
+    }
+
+    fn grow(&mut self) -> Option<*mut SlabHeader> {
+        let start = reserve_slab_area(self.slab_bytes())?;
+        map_slab_pages(start, self.slab_pages)?;
+
+        let slab = start as *mut SlabHeader;
+        unsafe {
+            let mut free = ptr::null_mut();
+            for i in (0..self.objects_per_slab).rev() {
+                let object = (start + self.first_object + i * self.object_size) as *mut FreeObject;
+                object.write(FreeObject { next: free });
+                free = object;
+            }
+            slab.write(SlabHeader {
+                next: self.slabs,
+                free,
+                in_use: 0,
+            });
+        }
+        self.slabs = slab;
+
+        self.stats.slabs += 1;
+        self.stats.pages += self.slab_pages;
+        self.stats.objects_total += self.objects_per_slab;
+        Some(slab)
+    }
+}
+
+// pages handed to slabs are never returned to the frame allocator,
+// empty slabs just stay cached in their owner.
+static SLAB_NEXT: Locked<usize> = Locked::new(SLAB_START);
+
+fn reserve_slab_area(size: usize) -> Option<usize> {
+    let mut next = SLAB_NEXT.lock();
+    let start = align_up(*next, size);
+    let end = start.checked_add(size)?;
+    if end > SLAB_START + SLAB_AREA_SIZE {
+        return None;
+    }
+    *next = end;
+    Some(start)
+}
+
+fn map_slab_pages(start: usize, pages: usize) -> Option<()> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = PAGE_ALLOCATOR.lock();
+    let mapper = mapper.as_mut()?;
+    let frame_allocator = frame_allocator.as_mut()?;
+
+    let first = Page::containing_address(VirtAddr::new(start as u64));
+    let flags = data_page_flags();
+    for page in Page::range(first, first + pages as u64) {
+        let mapped = frame_allocator.allocate_frame().and_then(|frame| {
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => Some(flush.flush()),
+                Err(_) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    None
+                }
+            }
+        });
+        if mapped.is_none() {
+            // give back what this slab got so far, its virtual range stays reserved
+            for page in Page::range(first, page) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            return None;
+        }
+    }
+    Some(())
+}
+
+static CACHES: Locked<Vec<&'static Locked<SlabCache>>> = Locked::new(Vec::new());
+
+/// create a named cache that stays alive for the whole kernel lifetime
+pub fn create_cache(name: &'static str, size: usize, align: usize,
+                    constructor: Option<fn(*mut u8)>) -> &'static Locked<SlabCache>
+{
+    let cache = Box::leak(Box::new(Locked::new(SlabCache::new(name, size, align, constructor))));
+    CACHES.lock().push(cache);
+    cache
+}
+
+pub fn dump_caches() {
+    serial_println!("{:<16} {:>8} {:>8} {:>8} {:>6} {:>6} {:>8} {:>8} {:>6}",
+                    "cache", "objsize", "active", "total", "slabs", "pages",
+                    "allocs", "frees", "failed");
+    for cache in CACHES.lock().iter() {
+        let cache = cache.lock();
+        let stats = cache.stats();
+        serial_println!("{:<16} {:>8} {:>8} {:>8} {:>6} {:>6} {:>8} {:>8} {:>6}",
+                        cache.name(), cache.object_size(), stats.objects_in_use,
+                        stats.objects_total, stats.slabs, stats.pages,
+                        stats.allocations, stats.frees, stats.failed);
+    }
+}
+
+/// a slab cache for objects of type `T`
+pub struct ObjectCache<T> {
+    cache: &'static Locked<SlabCache>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub fn new(name: &'static str) -> Self {
+        ObjectCache {
+            cache: create_cache(name, mem::size_of::<T>(), mem::align_of::<T>(), None),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        let ptr = NonNull::new(self.cache.lock().alloc() as *mut T)?;
+        unsafe {
+            ptr.as_ptr().write(value);
+        }
+        Some(ptr)
+    }
+
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        ptr::drop_in_place(ptr.as_ptr());
+        self.cache.lock().free(ptr.as_ptr() as *mut u8);
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.cache.lock().stats()
+    }
+}
+
+#[test_case]
+fn test_slab_reuse() {
+    let cache: ObjectCache<[u64; 4]> = ObjectCache::new("test-reuse");
+    let first = cache.alloc([1; 4]).unwrap();
+    unsafe {
+        assert_eq!(*first.as_ref(), [1; 4]);
+        cache.free(first);
+    }
+    let second = cache.alloc([2; 4]).unwrap();
+    assert_eq!(first, second);
+    assert_eq!(cache.stats().objects_in_use, 1);
+    assert_eq!(cache.stats().slabs, 1);
+}
+
+#[test_case]
+fn test_slab_grow() {
+    let cache: ObjectCache<u64> = ObjectCache::new("test-grow");
+    let mut objects = Vec::new();
+    for i in 0..1000 {
+        let object = cache.alloc(i).unwrap();
+        assert_eq!(object.as_ptr() as usize % mem::align_of::<u64>(), 0);
+        objects.push(object);
+    }
+    assert!(cache.stats().slabs > 1);
+    for (i, object) in objects.into_iter().enumerate() {
+        unsafe {
+            assert_eq!(*object.as_ref(), i as u64);
+            cache.free(object);
+        }
+    }
+    assert_eq!(cache.stats().objects_in_use, 0);
+}
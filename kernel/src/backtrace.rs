@@ -0,0 +1,317 @@
+use crate::{klogln, memory::{inspect, phys_to_virt}};
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::{
+    convert::TryInto,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// frames beyond this are left out
+pub const MAX_DEPTH: usize = 32;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+/// a function in the kernel image
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub address: u64,
+    pub size: u64,
+    /// as the linker has it, mangled
+    pub name: &'static str,
+}
+
+static SYMBOLS: OnceCell<Vec<Symbol>> = OnceCell::uninit();
+// a fault while walking would otherwise walk again, and fault again
+static WALKING: AtomicBool = AtomicBool::new(false);
+
+/// the return addresses of a stack, found by following the frame pointers
+pub struct Backtrace {
+    frames: [u64; MAX_DEPTH],
+    len: usize,
+    truncated: bool,
+    // the first frame is where an exception hit, not a return address
+    interrupted: bool,
+}
+
+impl Backtrace {
+    /// the stack of the caller
+    #[inline(always)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        Backtrace::walk(None, rbp)
+    }
+
+    /// the stack of code interrupted at `rip` with `rbp` as its frame pointer
+    pub fn from_frame(rip: u64, rbp: u64) -> Backtrace {
+        Backtrace::walk(Some(rip), rbp)
+    }
+
+    fn walk(rip: Option<u64>, mut rbp: u64) -> Backtrace {
+        let mut trace = Backtrace {
+            frames: [0; MAX_DEPTH],
+            len: 0,
+            truncated: false,
+            interrupted: rip.is_some(),
+        };
+        if WALKING.swap(true, Ordering::Acquire) {
+            return trace;
+        }
+        if let Some(rip) = rip {
+            trace.push(rip);
+        }
+        while rbp != 0 && !trace.truncated {
+            // a frame is the caller's rbp followed by the return address
+            if rbp % 8 != 0 || !is_mapped(rbp) || !is_mapped(rbp + 8) {
+                break;
+            }
+            let (next, return_address) = unsafe {
+                (*(rbp as *const u64), *((rbp + 8) as *const u64))
+            };
+            if return_address == 0 {
+                break;
+            }
+            trace.push(return_address);
+            // callers are further up the stack, anything else is garbage or a loop
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        WALKING.store(false, Ordering::Release);
+        trace
+    }
+
+    fn push(&mut self, address: u64) {
+        if self.len == MAX_DEPTH {
+            self.truncated = true;
+        } else {
+            self.frames[self.len] = address;
+            self.len += 1;
+        }
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+/// one frame per line, with the function when there is a symbol table
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.len == 0 {
+            return f.write_str("  <no frames>");
+        }
+        for (depth, &address) in self.frames().iter().enumerate() {
+            if depth != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  #{:<2} {:#018x}", depth, address)?;
+            // a return address may be just past the end of the calling function
+            let call = if depth == 0 && self.interrupted { address } else { address - 1 };
+            if let Some(symbol) = symbolize(call) {
+                write!(f, " {}+{:#x}", Demangle(symbol.name), address - symbol.address)?;
+            }
+        }
+        if self.truncated {
+            f.write_str("\n  ...")?;
+        }
+        Ok(())
+    }
+}
+
+// the walker may run in a panic while the mapper is held
+fn is_mapped(addr: u64) -> bool {
+    VirtAddr::try_new(addr).ok().and_then(inspect::translate).is_some()
+}
+
+/// the function `address` is in, once `init` found a symbol table
+pub fn symbolize(address: u64) -> Option<&'static Symbol> {
+    let symbols = SYMBOLS.try_get().ok()?;
+    let index = match symbols.binary_search_by_key(&address, |symbol| symbol.address) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let symbol = &symbols[index];
+    if symbol.size == 0 || address - symbol.address < symbol.size {
+        Some(symbol)
+    } else {
+        None
+    }
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(image.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(image.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(image.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+// the functions in the .symtab of a 64 bit little endian ELF file
+fn parse_symbols(image: &'static [u8]) -> Option<Vec<Symbol>> {
+    if image.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+    let section_headers = read_u64(image, 0x28)? as usize;
+    let section_count = read_u16(image, 0x3c)? as usize;
+    let section = |index: usize| section_headers + index * SECTION_HEADER_SIZE;
+
+    let symtab = (0..section_count)
+        .map(section)
+        .find(|&header| read_u32(image, header + 4) == Some(SHT_SYMTAB))?;
+    let symbols_offset = read_u64(image, symtab + 0x18)? as usize;
+    let symbols_size = read_u64(image, symtab + 0x20)? as usize;
+    let strtab = section(read_u32(image, symtab + 0x28)? as usize);
+    let strings = image.get(read_u64(image, strtab + 0x18)? as usize..)?;
+
+    let mut symbols = Vec::new();
+    for offset in (symbols_offset..symbols_offset + symbols_size).step_by(SYMBOL_SIZE) {
+        let entry = image.get(offset..offset + SYMBOL_SIZE)?;
+        let address = read_u64(entry, 8)?;
+        if entry[4] & 0xf != STT_FUNC || address == 0 {
+            continue;
+        }
+        let name = strings.get(read_u32(entry, 0)? as usize..)?;
+        let name = &name[..name.iter().position(|&byte| byte == 0)?];
+        if let Ok(name) = core::str::from_utf8(name) {
+            symbols.push(Symbol { address, size: read_u64(entry, 16)?, name });
+        }
+    }
+    symbols.sort_unstable_by_key(|symbol| symbol.address);
+    Some(symbols)
+}
+
+/// load the symbol table of the kernel image, which the bootloader leaves
+/// in memory as it was on disk. Needs the heap.
+pub fn init(memory_map: &MemoryMap) {
+    let image = memory_map.iter()
+        .filter(|region| region.region_type == MemoryRegionType::Kernel)
+        .map(|region| {
+            let start = phys_to_virt(PhysAddr::new(region.range.start_addr()));
+            let len = (region.range.end_addr() - region.range.start_addr()) as usize;
+            unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), len) }
+        })
+        .find(|image| image.starts_with(b"\x7fELF"));
+
+    match image.and_then(parse_symbols) {
+        Some(symbols) if !symbols.is_empty() => {
+            klogln!("backtrace: {} symbols in the kernel image", symbols.len());
+            SYMBOLS.try_init_once(|| symbols).ok();
+        }
+        _ => klogln!("backtrace: no symbol table, addresses only"),
+    }
+}
+
+/// a legacy mangled Rust name as it was written, `_ZN4core3fmt5write17h…E`
+/// becomes `core::fmt::write`. Anything else is left as it is.
+pub struct Demangle<'a>(pub &'a str);
+
+// the first length prefixed part and what follows it
+fn next_part(rest: &str) -> Option<(&str, &str)> {
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = rest[..digits].parse().ok()?;
+    Some((rest.get(digits..digits + len)?, &rest[digits + len..]))
+}
+
+// the trailing hash tells apart crates of the same name, not people
+fn is_hash(part: &str) -> bool {
+    part.len() == 17 && part.starts_with('h') && part[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn write_part(f: &mut fmt::Formatter, part: &str) -> fmt::Result {
+    // a leading underscore only keeps a part from starting with `$`
+    let mut rest = if part.starts_with("_$") { &part[1..] } else { part };
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+        } else if rest.starts_with('$') {
+            let end = match rest[1..].find('$') {
+                Some(end) => end + 1,
+                None => return f.write_str(rest),
+            };
+            let escaped = match &rest[1..end] {
+                "SP" => '@',
+                "BP" => '*',
+                "RF" => '&',
+                "LT" => '<',
+                "GT" => '>',
+                "LP" => '(',
+                "RP" => ')',
+                "C" => ',',
+                code if code.starts_with('u') => u32::from_str_radix(&code[1..], 16).ok()
+                    .and_then(core::char::from_u32)
+                    .unwrap_or('?'),
+                _ => '?',
+            };
+            write!(f, "{}", escaped)?;
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest.find(|c| c == '$' || c == '.').unwrap_or_else(|| rest.len()).max(1);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+// nothing is allocated, this runs in the panic path
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.0.starts_with("_ZN") || !self.0.ends_with('E') {
+            return f.write_str(self.0);
+        }
+        let parts = &self.0[3..self.0.len() - 1];
+        let mut rest = parts;
+        while !rest.is_empty() {
+            match next_part(rest) {
+                Some((_, next)) => rest = next,
+                None => return f.write_str(self.0),
+            }
+        }
+
+        let mut rest = parts;
+        let mut first = true;
+        while let Some((part, next)) = next_part(rest) {
+            rest = next;
+            if rest.is_empty() && is_hash(part) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            write_part(f, part)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_demangle() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", Demangle("_ZN4core3fmt5write17h0123456789abcdefE")), "core::fmt::write");
+    assert_eq!(format!("{}", Demangle("_ZN53_$LT$sos..time..Instant$u20$as$u20$core..ops..Add$GT$3add17h0123456789abcdefE")),
+               "<sos::time::Instant as core::ops::Add>::add");
+    assert_eq!(format!("{}", Demangle("memcpy")), "memcpy");
+}
+
+#[test_case]
+fn test_backtrace_capture() {
+    let trace = Backtrace::capture();
+    assert!(!trace.frames().is_empty());
+    assert!(trace.frames().len() <= MAX_DEPTH);
+}
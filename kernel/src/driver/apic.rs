@@ -0,0 +1,367 @@
+use super::ioapic::{IoApic, Polarity};
+use crate::memory::mmio::{map_mmio, CacheMode, MmioError, MmioRegion};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0xf_ffff_f000;
+// x2APIC registers are MSRs at this base plus the xAPIC offset / 16
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const REG_ID: u32 = 0x20;
+const REG_VERSION: u32 = 0x30;
+const REG_TPR: u32 = 0x80;
+const REG_EOI: u32 = 0xb0;
+const REG_SVR: u32 = 0xf0;
+const REG_ISR: u32 = 0x100;
+const REG_ESR: u32 = 0x280;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
+/// divides the bus clock feeding the timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+pub struct LocalApic {
+    // None in x2APIC mode, where the registers are MSRs
+    mmio: Option<MmioRegion>,
+}
+
+impl LocalApic {
+    fn read(&self, register: u32) -> u32 {
+        match &self.mmio {
+            Some(mmio) => mmio.read::<u32>(register as usize),
+            None => unsafe { Msr::new(X2APIC_MSR_BASE + (register >> 4)).read() as u32 },
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        match &self.mmio {
+            Some(mmio) => mmio.write::<u32>(register as usize, value),
+            None => unsafe { Msr::new(X2APIC_MSR_BASE + (register >> 4)).write(value as u64) },
+        }
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        self.mmio.is_none()
+    }
+
+    pub fn id(&self) -> u32 {
+        match self.mmio {
+            Some(_) => self.read(REG_ID) >> 24,
+            None => self.read(REG_ID),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.read(REG_VERSION) as u8
+    }
+
+    fn enable(&mut self, nmis: &[LocalNmi]) {
+        // LINT0 carries ExtINT from the PIC, which is masked for good, and
+        // LINT1 the NMI on legacy wiring, unless firmware says otherwise
+        let mut lint = [LVT_MASKED, lvt_nmi(Polarity::ISA)];
+        let id = self.id();
+        for nmi in nmis.iter().filter(|nmi| nmi.apic_id.map_or(true, |apic_id| apic_id == id)) {
+            if let Some(lvt) = lint.get_mut(nmi.lint as usize) {
+                *lvt = lvt_nmi(nmi.polarity);
+            }
+        }
+        self.write(REG_LVT_LINT0, lint[0]);
+        self.write(REG_LVT_LINT1, lint[1]);
+        self.write(REG_LVT_ERROR, LVT_MASKED);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TPR, 0);
+        self.write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        // writing the error status register clears it
+        self.write(REG_ESR, 0);
+        self.eoi();
+    }
+
+    pub fn eoi(&mut self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// whether `vector` was delivered by this APIC and is not acknowledged yet
+    pub fn in_service(&self, vector: u8) -> bool {
+        let register = REG_ISR + 0x10 * (vector as u32 / 32);
+        self.read(register) & 1 << (vector % 32) != 0
+    }
+
+    /// start the timer, it fires `vector` after `initial_count` ticks of
+    /// the divided bus clock
+    pub fn set_timer(&mut self, vector: u8, mode: TimerMode, divide: TimerDivide, initial_count: u32) {
+        let mut lvt = vector as u32;
+        if mode == TimerMode::Periodic {
+            lvt |= LVT_PERIODIC;
+        }
+        self.write(REG_TIMER_DIVIDE, divide as u32);
+        self.write(REG_LVT_TIMER, lvt);
+        self.write(REG_TIMER_INITIAL, initial_count);
+    }
+
+    /// keep the timer counting without raising its interrupt
+    pub fn mask_timer(&mut self) {
+        let lvt = self.read(REG_LVT_TIMER);
+        self.write(REG_LVT_TIMER, lvt | LVT_MASKED);
+    }
+
+    pub fn stop_timer(&mut self) {
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL, 0);
+    }
+
+    pub fn timer_count(&self) -> u32 {
+        self.read(REG_TIMER_CURRENT)
+    }
+
+    fn send_command(&mut self, destination: u32, command: u32) {
+        match self.mmio {
+            Some(_) => {
+                self.write(REG_ICR_HIGH, destination << 24);
+                self.write(REG_ICR_LOW, command);
+                while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::sync::atomic::spin_loop_hint();
+                }
+            }
+            // one 64 bit write, and there is no delivery status to wait for
+            None => unsafe {
+                Msr::new(X2APIC_MSR_BASE + (REG_ICR_LOW >> 4))
+                    .write((destination as u64) << 32 | command as u64);
+            },
+        }
+    }
+
+    pub fn send_ipi(&mut self, destination: u32, vector: u8) {
+        self.send_command(destination, vector as u32);
+    }
+
+    pub fn send_ipi_to_others(&mut self, vector: u8) {
+        self.send_command(0, ICR_ALL_EXCLUDING_SELF | vector as u32);
+    }
+
+    /// INIT followed by STARTUP at `page`, the sequence that wakes an AP
+    pub fn send_init(&mut self, destination: u32) {
+        self.send_command(destination, ICR_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    pub fn send_startup(&mut self, destination: u32, page: u8) {
+        self.send_command(destination, ICR_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+    }
+}
+
+fn lvt_nmi(polarity: Polarity) -> u32 {
+    let mut lvt = LVT_NMI;
+    if polarity.active_low {
+        lvt |= LVT_ACTIVE_LOW;
+    }
+    if polarity.level_triggered {
+        lvt |= LVT_LEVEL_TRIGGERED;
+    }
+    lvt
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// an ISA IRQ that is not wired to the GSI with the same number
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+}
+
+/// a local APIC pin wired to NMI
+#[derive(Debug, Clone, Copy)]
+pub struct LocalNmi {
+    /// None for every processor
+    pub apic_id: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+}
+
+/// the interrupt controllers of the machine, as described by the MADT
+#[derive(Debug, Clone)]
+pub struct Topology {
+    pub local_apic: PhysAddr,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalNmi>,
+}
+
+impl Topology {
+    /// the addresses every PC uses when firmware says nothing else
+    pub fn legacy() -> Self {
+        Topology {
+            local_apic: PhysAddr::new(0xfee0_0000),
+            io_apics: alloc::vec![IoApicInfo { address: PhysAddr::new(0xfec0_0000), gsi_base: 0 }],
+            // the PIT is wired to pin 2 on practically every chipset
+            overrides: alloc::vec![InterruptOverride { isa_irq: 0, gsi: 2, polarity: Polarity::ISA }],
+            nmis: alloc::vec![LocalNmi { apic_id: None, lint: 1, polarity: Polarity::ISA }],
+        }
+    }
+
+    /// the GSI and polarity an ISA IRQ arrives on
+    pub fn isa_route(&self, irq: u8) -> (u32, Polarity) {
+        match self.overrides.iter().find(|o| o.isa_irq == irq) {
+            Some(o) => (o.gsi, o.polarity),
+            None => (irq as u32, Polarity::ISA),
+        }
+    }
+}
+
+pub struct Apic {
+    pub local: LocalApic,
+    pub io_apics: Vec<IoApic>,
+    topology: Topology,
+}
+
+impl Apic {
+    fn io_apic_for(&mut self, gsi: u32) -> Option<&mut IoApic> {
+        self.io_apics.iter_mut().find(|ioapic| ioapic.handles(gsi))
+    }
+
+    /// deliver ISA `irq` as `vector` to this CPU
+    pub fn route_isa_irq(&mut self, irq: u8, vector: u8) -> bool {
+        let (gsi, polarity) = self.topology.isa_route(irq);
+        let destination = self.local.id() as u8;
+        match self.io_apic_for(gsi) {
+            Some(ioapic) => {
+                ioapic.route(gsi, vector, destination, polarity);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn mask_isa_irq(&mut self, irq: u8) {
+        let (gsi, _) = self.topology.isa_route(irq);
+        if let Some(ioapic) = self.io_apic_for(gsi) {
+            ioapic.mask(gsi);
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub static APIC: Mutex<Option<Apic>> = Mutex::new(None);
+
+pub fn supported() -> (bool, bool) {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    (cpuid.edx & (1 << 9) != 0, cpuid.ecx & (1 << 21) != 0)
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// acknowledge `vector` if the local APIC delivered it, a software
+/// interrupt must not take the acknowledgement of a real one
+pub fn end_of_interrupt(vector: u8) {
+    if let Some(apic) = APIC.lock().as_mut() {
+        if apic.local.in_service(vector) {
+            apic.local.eoi();
+        }
+    }
+}
+
+/// switch the local APIC on, in x2APIC mode when the CPU has it,
+/// and map the IOAPICs of `topology` with every line masked.
+pub fn init(topology: Topology) -> Result<(), MmioError> {
+    // nothing changes until every controller is mapped,
+    // so that a failure leaves the PIC working
+    let mut io_apics = Vec::new();
+    for info in topology.io_apics.iter() {
+        io_apics.push(IoApic::new(info.address, info.gsi_base)?);
+    }
+
+    let mut base = Msr::new(IA32_APIC_BASE);
+    let (_, x2apic) = supported();
+    let mmio = unsafe {
+        let value = base.read();
+        if x2apic {
+            // x2APIC mode can only be entered from enabled xAPIC mode
+            base.write(value | APIC_BASE_ENABLE);
+            base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            None
+        } else {
+            let address = PhysAddr::new(value & APIC_BASE_ADDRESS);
+            let mmio = map_mmio(address, 0x400, CacheMode::Uncacheable)?;
+            base.write(value | APIC_BASE_ENABLE);
+            Some(mmio)
+        }
+    };
+
+    let mut local = LocalApic { mmio };
+    local.enable(&topology.nmis);
+    *APIC.lock() = Some(Apic { local, io_apics, topology });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[test_case]
+fn test_local_apic_timer() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    if !is_enabled() {
+        return;
+    }
+    without_interrupts(|| {
+        let mut apic = APIC.lock();
+        let local = &mut apic.as_mut().unwrap().local;
+        // masked right away, only the counter is of interest
+        local.set_timer(SPURIOUS_VECTOR, TimerMode::OneShot, TimerDivide::By1, u32::max_value());
+        local.mask_timer();
+        let first = local.timer_count();
+        for _ in 0..1000 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        assert!(local.timer_count() < first);
+        local.stop_timer();
+    });
+}
@@ -0,0 +1,60 @@
+use crate::memory::mmio::{map_mmio, CacheMode, MmioError, MmioRegion};
+use x86_64::PhysAddr;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_COUNTER: usize = 0x0f0;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CAPABILITY_64BIT: u64 = 1 << 13;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// the main counter of an HPET, used as a free running clock
+pub struct Hpet {
+    region: MmioRegion,
+    period: u64,
+    counter_64bit: bool,
+}
+
+impl Hpet {
+    /// map the HPET at `address` and start its main counter
+    pub fn new(address: PhysAddr) -> Result<Self, MmioError> {
+        let region = map_mmio(address, 0x400, CacheMode::Uncacheable)?;
+        let capabilities = region.read::<u64>(REG_CAPABILITIES);
+        let hpet = Hpet {
+            region,
+            period: capabilities >> 32,
+            counter_64bit: capabilities & CAPABILITY_64BIT != 0,
+        };
+        let config = hpet.region.read::<u64>(REG_CONFIG);
+        hpet.region.write::<u64>(REG_CONFIG, config | CONFIG_ENABLE);
+        Ok(hpet)
+    }
+
+    /// the length of one count, in femtoseconds
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    pub fn frequency(&self) -> u64 {
+        FEMTOSECONDS_PER_SECOND / self.period
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+
+    pub fn counter(&self) -> u64 {
+        if self.counter_64bit {
+            self.region.read::<u64>(REG_COUNTER)
+        } else {
+            self.region.read::<u32>(REG_COUNTER) as u64
+        }
+    }
+
+    /// counts converted to nanoseconds
+    pub fn nanos(&self, counts: u64) -> u64 {
+        (counts as u128 * self.period as u128 / 1_000_000) as u64
+    }
+}
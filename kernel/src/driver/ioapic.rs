@@ -0,0 +1,106 @@
+use crate::memory::mmio::{map_mmio, CacheMode, MmioError, MmioRegion};
+use x86_64::PhysAddr;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+/// how an interrupt line signals, as given by the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Polarity {
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl Polarity {
+    /// ISA interrupts are edge triggered and active high
+    pub const ISA: Polarity = Polarity { active_low: false, level_triggered: false };
+}
+
+pub struct IoApic {
+    region: MmioRegion,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    /// map the IOAPIC at `address` serving the interrupts from `gsi_base` on,
+    /// every line starts masked
+    pub fn new(address: PhysAddr, gsi_base: u32) -> Result<Self, MmioError> {
+        let region = map_mmio(address, 0x20, CacheMode::Uncacheable)?;
+        let mut ioapic = IoApic { region, gsi_base, entries: 0 };
+        ioapic.entries = (ioapic.read(REG_VERSION) >> 16 & 0xff) + 1;
+        for index in 0..ioapic.entries {
+            ioapic.write_entry(index, ENTRY_MASKED);
+        }
+        Ok(ioapic)
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        self.region.write::<u32>(IOREGSEL, register);
+        self.region.read::<u32>(IOWIN)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.region.write::<u32>(IOREGSEL, register);
+        self.region.write::<u32>(IOWIN, value);
+    }
+
+    fn read_entry(&mut self, index: u32) -> u64 {
+        let low = self.read(REG_REDIRECTION + index * 2) as u64;
+        let high = self.read(REG_REDIRECTION + index * 2 + 1) as u64;
+        low | high << 32
+    }
+
+    // the low half holds the mask bit, write it last so that the entry
+    // is never live with a stale destination
+    fn write_entry(&mut self, index: u32, entry: u64) {
+        self.write(REG_REDIRECTION + index * 2 + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION + index * 2, entry as u32);
+    }
+
+    pub fn id(&mut self) -> u8 {
+        (self.read(REG_ID) >> 24 & 0xf) as u8
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    /// deliver `gsi` as `vector` to the local APIC `destination`
+    pub fn route(&mut self, gsi: u32, vector: u8, destination: u8, polarity: Polarity) {
+        assert!(self.handles(gsi), "GSI {} is not on this IOAPIC", gsi);
+        let mut entry = vector as u64 | (destination as u64) << 56;
+        if polarity.active_low {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if polarity.level_triggered {
+            entry |= ENTRY_LEVEL;
+        }
+        self.write_entry(gsi - self.gsi_base, entry);
+    }
+
+    pub fn mask(&mut self, gsi: u32) {
+        let index = gsi - self.gsi_base;
+        let entry = self.read_entry(index);
+        self.write_entry(index, entry | ENTRY_MASKED);
+    }
+
+    pub fn unmask(&mut self, gsi: u32) {
+        let index = gsi - self.gsi_base;
+        let entry = self.read_entry(index);
+        self.write_entry(index, entry & !ENTRY_MASKED);
+    }
+
+    pub fn is_masked(&mut self, gsi: u32) -> bool {
+        let index = gsi - self.gsi_base;
+        self.read_entry(index) & ENTRY_MASKED != 0
+    }
+}
@@ -1,2 +1,8 @@
 pub mod serial;
+pub mod pic;
+pub mod apic;
+pub mod ioapic;
+pub mod pit;
+pub mod hpet;
+pub mod rtc;
 pub mod vga_buffer;
\ No newline at end of file
@@ -0,0 +1,187 @@
+use x86_64::instructions::port::Port;
+
+const CMD_INIT: u8 = 0x11;
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_IRR: u8 = 0x0a;
+const CMD_READ_ISR: u8 = 0x0b;
+const MODE_8086: u8 = 0x01;
+
+// the slave is wired to IRQ2 of the master
+const CASCADE_IRQ: u8 = 2;
+// the line a PIC reports when an interrupt went away before it was acknowledged
+const SPURIOUS_LINE: u8 = 7;
+
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    const unsafe fn new(offset: u8, command: u16, data: u16) -> Self {
+        Pic {
+            offset,
+            command: Port::new(command),
+            data: Port::new(data),
+        }
+    }
+
+    fn handles(&self, vector: u8) -> bool {
+        self.offset <= vector && vector < self.offset + 8
+    }
+
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(CMD_END_OF_INTERRUPT);
+    }
+
+    unsafe fn read_register(&mut self, command: u8) -> u8 {
+        self.command.write(command);
+        self.command.read()
+    }
+}
+
+/// the master and slave 8259 of the PC, remapped to `offset..offset + 16`
+pub struct ChainedPics {
+    pics: [Pic; 2],
+    spurious: [usize; 2],
+}
+
+impl ChainedPics {
+    pub const unsafe fn new(master_offset: u8, slave_offset: u8) -> Self {
+        ChainedPics {
+            pics: [Pic::new(master_offset, 0x20, 0x21), Pic::new(slave_offset, 0xa0, 0xa1)],
+            spurious: [0; 2],
+        }
+    }
+
+    /// remap both PICs to their offsets and mask every line,
+    /// drivers unmask the lines they handle
+    pub unsafe fn initialize(&mut self) {
+        // the PICs are slow, give them time between the writes
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        let mut wait = || wait_port.write(0);
+
+        for pic in self.pics.iter_mut() {
+            pic.command.write(CMD_INIT);
+            wait();
+        }
+        self.pics[0].data.write(self.pics[0].offset);
+        wait();
+        self.pics[1].data.write(self.pics[1].offset);
+        wait();
+        self.pics[0].data.write(1 << CASCADE_IRQ);
+        wait();
+        self.pics[1].data.write(CASCADE_IRQ);
+        wait();
+        for pic in self.pics.iter_mut() {
+            pic.data.write(MODE_8086);
+            wait();
+        }
+
+        self.set_mask(0xffff & !(1 << CASCADE_IRQ));
+    }
+
+    /// mask every line, for when the APIC takes over
+    pub unsafe fn disable(&mut self) {
+        self.set_mask(0xffff);
+    }
+
+    /// one bit per IRQ, set bits are masked
+    pub fn mask(&mut self) -> u16 {
+        unsafe { self.pics[0].data.read() as u16 | (self.pics[1].data.read() as u16) << 8 }
+    }
+
+    pub fn set_mask(&mut self, mask: u16) {
+        unsafe {
+            self.pics[0].data.write(mask as u8);
+            self.pics[1].data.write((mask >> 8) as u8);
+        }
+    }
+
+    pub fn mask_irq(&mut self, irq: u8) {
+        assert!(irq < 16, "no such IRQ: {}", irq);
+        let mask = self.mask() | 1 << irq;
+        self.set_mask(mask);
+    }
+
+    pub fn unmask_irq(&mut self, irq: u8) {
+        assert!(irq < 16, "no such IRQ: {}", irq);
+        let mut mask = self.mask() & !(1 << irq);
+        // lines of the slave only get through the cascade
+        if irq >= 8 {
+            mask &= !(1 << CASCADE_IRQ);
+        }
+        self.set_mask(mask);
+    }
+
+    /// interrupts requested but not yet serviced, one bit per IRQ
+    pub fn read_irr(&mut self) -> u16 {
+        unsafe {
+            self.pics[0].read_register(CMD_READ_IRR) as u16
+                | (self.pics[1].read_register(CMD_READ_IRR) as u16) << 8
+        }
+    }
+
+    /// interrupts being serviced, one bit per IRQ
+    pub fn read_isr(&mut self) -> u16 {
+        unsafe {
+            self.pics[0].read_register(CMD_READ_ISR) as u16
+                | (self.pics[1].read_register(CMD_READ_ISR) as u16) << 8
+        }
+    }
+
+    pub fn handles_interrupt(&self, vector: u8) -> bool {
+        self.pics.iter().any(|pic| pic.handles(vector))
+    }
+
+    /// whether `vector` is a spurious IRQ7 or IRQ15, which must not be
+    /// handled. For IRQ15 the master still saw the cascade and gets its EOI.
+    pub fn check_spurious(&mut self, vector: u8) -> bool {
+        let index = match self.pics.iter().position(|pic| vector == pic.offset + SPURIOUS_LINE) {
+            Some(index) => index,
+            None => return false,
+        };
+        let in_service = unsafe { self.pics[index].read_register(CMD_READ_ISR) } & 1 << SPURIOUS_LINE;
+        if in_service != 0 {
+            return false;
+        }
+
+        self.spurious[index] += 1;
+        if index == 1 {
+            unsafe { self.pics[0].end_of_interrupt() };
+        }
+        true
+    }
+
+    /// spurious interrupts seen on IRQ7 and IRQ15
+    pub fn spurious_counts(&self) -> (usize, usize) {
+        (self.spurious[0], self.spurious[1])
+    }
+
+    pub unsafe fn notify_end_of_interrupt(&mut self, vector: u8) {
+        if !self.handles_interrupt(vector) {
+            return;
+        }
+        if self.pics[1].handles(vector) {
+            self.pics[1].end_of_interrupt();
+        }
+        self.pics[0].end_of_interrupt();
+    }
+}
+
+#[test_case]
+fn test_pic_mask() {
+    use crate::interrupts::PICS;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let saved = pics.mask();
+        pics.unmask_irq(12);
+        assert_eq!(pics.mask() & (1 << 12 | 1 << CASCADE_IRQ), 0);
+        pics.mask_irq(12);
+        assert_ne!(pics.mask() & 1 << 12, 0);
+        pics.set_mask(saved);
+        assert_eq!(pics.read_isr(), 0);
+    });
+}
@@ -0,0 +1,74 @@
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// the input clock of all three channels, in Hz
+pub const FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is its output
+const CONTROL: u16 = 0x61;
+
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const SELECT_CHANNEL2: u8 = 0b10 << 6;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_ONE_SHOT: u8 = 0b000 << 1;
+const MODE_RATE: u8 = 0b010 << 1;
+
+const CONTROL_GATE: u8 = 1 << 0;
+const CONTROL_SPEAKER: u8 = 1 << 1;
+const CONTROL_OUTPUT: u8 = 1 << 5;
+
+// the longest a single count of channel 2 can wait, in microseconds
+const MAX_WAIT: u64 = 50_000;
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn divisor(hz: u32) -> u16 {
+    (FREQUENCY / hz.max(1)).max(1).min(0xffff) as u16
+}
+
+/// make channel 0 raise IRQ0 `hz` times a second, returns the rate it got
+pub fn set_periodic(hz: u32) -> u32 {
+    let divisor = divisor(hz);
+    let _lock = LOCK.lock();
+    unsafe {
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL0 | ACCESS_LOW_HIGH | MODE_RATE);
+        let mut data = Port::<u8>::new(CHANNEL0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+    FREQUENCY / divisor as u32
+}
+
+/// spin for `microseconds` on channel 2, which needs no interrupts and
+/// is what the other clocks are calibrated against
+pub fn busy_wait(microseconds: u64) {
+    let mut left = microseconds;
+    while left > 0 {
+        let wait = left.min(MAX_WAIT);
+        wait_once((wait * FREQUENCY as u64 / 1_000_000).max(1) as u16);
+        left -= wait;
+    }
+}
+
+fn wait_once(count: u16) {
+    let _lock = LOCK.lock();
+    unsafe {
+        let mut control = Port::<u8>::new(CONTROL);
+        let saved = control.read();
+        // gate low while the count is loaded, and keep the speaker quiet
+        control.write(saved & !(CONTROL_GATE | CONTROL_SPEAKER));
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL2 | ACCESS_LOW_HIGH | MODE_ONE_SHOT);
+        let mut data = Port::<u8>::new(CHANNEL2);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        // the count starts on the rising edge of the gate
+        control.write(saved & !CONTROL_SPEAKER | CONTROL_GATE);
+        while control.read() & CONTROL_OUTPUT == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        control.write(saved);
+    }
+}
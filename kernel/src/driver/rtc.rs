@@ -0,0 +1,273 @@
+use crate::{
+    irq::{self, HandlerId, IrqError, IrqReturn, Sharing},
+    time::DateTime,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+// bit 7 of the index port masks NMI, keep it clear
+const NMI_DISABLE: u8 = 1 << 7;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_ALARM_SECONDS: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_ALARM_MINUTES: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_ALARM_HOURS: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0a;
+const REG_B: u8 = 0x0b;
+const REG_C: u8 = 0x0c;
+
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const A_RATE_MASK: u8 = 0x0f;
+const B_24_HOUR: u8 = 1 << 1;
+const B_BINARY: u8 = 1 << 2;
+const B_ALARM_INTERRUPT: u8 = 1 << 5;
+const B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const C_ALARM: u8 = 1 << 5;
+const C_PERIODIC: u8 = 1 << 6;
+const C_INTERRUPT: u8 = 1 << 7;
+const HOUR_PM: u8 = 1 << 7;
+
+// where most firmware keeps the century, when the FADT does not say
+const DEFAULT_CENTURY_REGISTER: u8 = 0x32;
+
+static LOCK: Mutex<()> = Mutex::new(());
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+static PERIODIC_COUNT: AtomicU64 = AtomicU64::new(0);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+unsafe fn read_register(register: u8) -> u8 {
+    Port::<u8>::new(INDEX).write(register & !NMI_DISABLE);
+    Port::<u8>::new(DATA).read()
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+    Port::<u8>::new(INDEX).write(register & !NMI_DISABLE);
+    Port::<u8>::new(DATA).write(value);
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// use the century register the FADT names, 0 for none
+pub fn set_century_register(register: u8) {
+    CENTURY_REGISTER.store(register, Ordering::Relaxed);
+}
+
+// the registers as they are, in whatever format the firmware chose
+#[derive(PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+unsafe fn read_raw(century_register: u8) -> Raw {
+    // an update takes about 2 ms once a second, and the registers are
+    // garbage while it is running
+    while read_register(REG_A) & A_UPDATE_IN_PROGRESS != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    Raw {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: if century_register != 0 { read_register(century_register) } else { 0 },
+    }
+}
+
+/// the date and time the RTC keeps, taken to be UTC
+pub fn read() -> DateTime {
+    let century_register = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => DEFAULT_CENTURY_REGISTER,
+        register => register,
+    };
+
+    let (raw, format) = without_interrupts(|| {
+        let _lock = LOCK.lock();
+        unsafe {
+            // an update may still start between the check and the reads,
+            // read until two agree
+            let mut raw = read_raw(century_register);
+            loop {
+                let again = read_raw(century_register);
+                if again == raw {
+                    break;
+                }
+                raw = again;
+            }
+            (raw, read_register(REG_B))
+        }
+    });
+
+    let binary = format & B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if format & B_24_HOUR == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if raw.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match decode(raw.century) {
+        century @ 19..=21 => century as u16,
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+fn update_b(set: u8, clear: u8) {
+    without_interrupts(|| {
+        let _lock = LOCK.lock();
+        unsafe {
+            let b = read_register(REG_B);
+            write_register(REG_B, (b & !clear) | set);
+            // a pending flag would keep IRQ8 from firing again
+            read_register(REG_C);
+        }
+    });
+}
+
+/// raise IRQ8 at 32768 >> (`rate` - 1) Hz, `rate` from 3 (8 kHz) to 15 (2 Hz)
+pub fn enable_periodic(rate: u8) {
+    assert!(rate >= 3 && rate <= 15, "RTC rate out of range: {}", rate);
+    without_interrupts(|| {
+        let _lock = LOCK.lock();
+        unsafe {
+            let a = read_register(REG_A);
+            write_register(REG_A, (a & !A_RATE_MASK) | rate);
+        }
+    });
+    update_b(B_PERIODIC_INTERRUPT, 0);
+}
+
+pub fn disable_periodic() {
+    update_b(0, B_PERIODIC_INTERRUPT);
+}
+
+/// periodic interrupts seen so far
+pub fn periodic_count() -> u64 {
+    PERIODIC_COUNT.load(Ordering::Relaxed)
+}
+
+/// raise IRQ8 once a day at the given time of the RTC
+pub fn set_alarm(hour: u8, minute: u8, second: u8) {
+    ALARM_FIRED.store(false, Ordering::Relaxed);
+    without_interrupts(|| {
+        let _lock = LOCK.lock();
+        unsafe {
+            let b = read_register(REG_B);
+            let encode = |value: u8| if b & B_BINARY != 0 { value } else { to_bcd(value) };
+            let hour = if b & B_24_HOUR != 0 {
+                encode(hour)
+            } else {
+                let pm = if hour >= 12 { HOUR_PM } else { 0 };
+                encode(match hour % 12 { 0 => 12, hour => hour }) | pm
+            };
+            write_register(REG_ALARM_SECONDS, encode(second));
+            write_register(REG_ALARM_MINUTES, encode(minute));
+            write_register(REG_ALARM_HOURS, hour);
+        }
+    });
+    update_b(B_ALARM_INTERRUPT, 0);
+}
+
+pub fn cancel_alarm() {
+    update_b(0, B_ALARM_INTERRUPT);
+}
+
+/// completes when the alarm goes off
+pub struct Alarm {
+    _private: (),
+}
+
+impl Future for Alarm {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ALARM_FIRED.swap(false, Ordering::Relaxed) {
+            return Poll::Ready(());
+        }
+        ALARM_WAKER.register(cx.waker());
+        if ALARM_FIRED.swap(false, Ordering::Relaxed) {
+            ALARM_WAKER.take();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+pub fn alarm() -> Alarm {
+    Alarm { _private: () }
+}
+
+// acknowledge IRQ8
+fn handle_interrupt() -> IrqReturn {
+    // reading C is the acknowledgement, IRQ8 stays quiet until then
+    let flags = unsafe {
+        Port::<u8>::new(INDEX).write(REG_C);
+        Port::<u8>::new(DATA).read()
+    };
+    if flags & C_PERIODIC != 0 {
+        PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if flags & C_ALARM != 0 {
+        ALARM_FIRED.store(true, Ordering::Relaxed);
+        ALARM_WAKER.wake();
+    }
+    if flags & C_INTERRUPT != 0 {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
+}
+
+/// take IRQ8, which the periodic interrupt and the alarm arrive on
+pub fn init() -> Result<HandlerId, IrqError> {
+    irq::register_irq(8, "rtc", Sharing::Exclusive, handle_interrupt)
+}
+
+#[test_case]
+fn test_rtc_read() {
+    let first = read();
+    assert!(first.year >= 2020);
+    assert!(first.month >= 1 && first.month <= 12);
+    assert!(first.day >= 1 && first.day <= 31);
+    assert!(first.hour < 24 && first.minute < 60 && first.second < 60);
+    assert!(read() >= first);
+}
@@ -2,6 +2,9 @@ use spin::Mutex;
 use lazy_static::lazy_static;
 use x86_64::instructions::port::Port;
 use core::fmt::{Write, Result, Arguments};
+use crate::{print, println, irq::{self, HandlerId, IrqError, IrqReturn, Sharing}};
+
+const COM1_IRQ: u8 = 4;
 
 pub struct Serial {
     data: Port<u8>,
@@ -49,6 +52,17 @@ impl Serial {
         }
     }
 
+    /// a byte if one arrived, without waiting
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe {
+            if self.line_sts.read() & 1 == 0 {
+                None
+            } else {
+                Some(self.data.read())
+            }
+        }
+    }
+
     pub fn receive(&mut self) -> u8 {
         unsafe {
             while self.line_sts.read() & 1 == 0 {
@@ -80,6 +94,25 @@ lazy_static! {
     };
 }
 
+/// print what arrives on COM1 to the screen
+pub fn enable_echo() -> core::result::Result<HandlerId, IrqError> {
+    irq::register_irq(COM1_IRQ, "com1", Sharing::Exclusive, || {
+        let data = match COM1.lock().try_receive() {
+            Some(data) => data,
+            None => return IrqReturn::NotMine,
+        };
+
+        // TODO: SET A BUFFER FOR COMMANDS
+
+        match data {
+            0x0A | 0x0D => println!(),
+            0x20..0x7F => print!("{}", data as char),
+            _ => (),
+        }
+        IrqReturn::Handled
+    })
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
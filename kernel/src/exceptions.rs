@@ -0,0 +1,400 @@
+use crate::{
+    backtrace::Backtrace,
+    gdb, gdt, log,
+    memory::{self, address_space, data_page_flags, MAPPER, PAGE_ALLOCATOR},
+    oom, task,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::{
+        idt::{InterruptDescriptorTable, PageFaultErrorCode},
+        paging::{mapper::MapToError, PhysFrame, Size4KiB},
+    },
+};
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NMI: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const COPROCESSOR_SEGMENT_OVERRUN: u8 = 9;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const SECURITY_EXCEPTION: u8 = 30;
+
+const NAMES: [&str; 32] = [
+    "DIVIDE ERROR", "DEBUG", "NON-MASKABLE INTERRUPT", "BREAKPOINT",
+    "OVERFLOW", "BOUND RANGE EXCEEDED", "INVALID OPCODE", "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT", "COPROCESSOR SEGMENT OVERRUN", "INVALID TSS", "SEGMENT NOT PRESENT",
+    "STACK-SEGMENT FAULT", "GENERAL PROTECTION FAULT", "PAGE FAULT", "RESERVED",
+    "X87 FLOATING POINT", "ALIGNMENT CHECK", "MACHINE CHECK", "SIMD FLOATING POINT",
+    "VIRTUALIZATION", "CONTROL PROTECTION", "RESERVED", "RESERVED",
+    "RESERVED", "RESERVED", "RESERVED", "RESERVED",
+    "HYPERVISOR INJECTION", "VMM COMMUNICATION", "SECURITY EXCEPTION", "RESERVED",
+];
+
+// every stub pushes a zero error code if the CPU did not, then the
+// vector, then all general purpose registers, and hands them to
+// `sos_exception_dispatch`. The stack ends up 16 byte aligned.
+global_asm!(r#"
+.intel_syntax noprefix
+.macro exception_stub vector
+.global sos_exception_\vector
+sos_exception_\vector:
+    push 0
+    push \vector
+    jmp sos_exception_common
+.endm
+.macro exception_stub_error_code vector
+.global sos_exception_\vector
+sos_exception_\vector:
+    push \vector
+    jmp sos_exception_common
+.endm
+
+exception_stub 0
+exception_stub 1
+exception_stub 2
+exception_stub 3
+exception_stub 4
+exception_stub 5
+exception_stub 6
+exception_stub 7
+exception_stub_error_code 8
+exception_stub 9
+exception_stub_error_code 10
+exception_stub_error_code 11
+exception_stub_error_code 12
+exception_stub_error_code 13
+exception_stub_error_code 14
+exception_stub 16
+exception_stub_error_code 17
+exception_stub 18
+exception_stub 19
+exception_stub 20
+exception_stub_error_code 30
+
+sos_exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call sos_exception_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+.att_syntax
+"#);
+
+extern "C" {
+    fn sos_exception_0();
+    fn sos_exception_1();
+    fn sos_exception_2();
+    fn sos_exception_3();
+    fn sos_exception_4();
+    fn sos_exception_5();
+    fn sos_exception_6();
+    fn sos_exception_7();
+    fn sos_exception_8();
+    fn sos_exception_9();
+    fn sos_exception_10();
+    fn sos_exception_11();
+    fn sos_exception_12();
+    fn sos_exception_13();
+    fn sos_exception_14();
+    fn sos_exception_16();
+    fn sos_exception_17();
+    fn sos_exception_18();
+    fn sos_exception_19();
+    fn sos_exception_20();
+    fn sos_exception_30();
+}
+
+/// the state of the interrupted code, as the stubs saved it
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    pub fn name(&self) -> &'static str {
+        NAMES.get(self.vector as usize).copied().unwrap_or("UNKNOWN")
+    }
+
+    /// whether the exception interrupted ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RIP {:016x} CS {:04x} RFLAGS {:016x} RSP {:016x} SS {:04x}",
+                 self.rip, self.cs, self.rflags, self.rsp, self.ss)?;
+        writeln!(f, "RAX {:016x} RBX {:016x} RCX {:016x} RDX {:016x}",
+                 self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI {:016x} RDI {:016x} RBP {:016x} R8  {:016x}",
+                 self.rsi, self.rdi, self.rbp, self.r8)?;
+        writeln!(f, "R9  {:016x} R10 {:016x} R11 {:016x} R12 {:016x}",
+                 self.r9, self.r10, self.r11, self.r12)?;
+        writeln!(f, "R13 {:016x} R14 {:016x} R15 {:016x}", self.r13, self.r14, self.r15)?;
+        write!(f, "CR0 {:016x} CR2 {:016x} CR3 {:016x} CR4 {:016x}",
+               Cr0::read_raw(), Cr2::read().as_u64(),
+               Cr3::read().0.start_address().as_u64(), Cr4::read_raw())
+    }
+}
+
+/// what the error code of a selector fault points at
+struct SelectorError(u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("no selector");
+        }
+        let table = match self.0 >> 1 & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} index {}", table, self.0 >> 3 & 0x1fff)?;
+        if self.0 & 1 != 0 {
+            f.write_str(", raised by an external event")?;
+        }
+        Ok(())
+    }
+}
+
+// an exception may hit while the log or the UART is held, never wait for them
+macro_rules! report {
+    ($($arg:tt)*) => (log::log_nonblocking(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+fn report(frame: &ExceptionFrame) {
+    let mode = if frame.is_user() { "user" } else { "kernel" };
+    report!("EXCEPTION: {} (vector {}) in {} mode at {:#x}",
+            frame.name(), frame.vector, mode, frame.rip);
+    match frame.vector as u8 {
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION =>
+            report!("error code {:#x}: {}", frame.error_code, SelectorError(frame.error_code)),
+        PAGE_FAULT => report!("error code {:#x}: {:?} at {:#x}", frame.error_code,
+                              PageFaultErrorCode::from_bits_truncate(frame.error_code),
+                              Cr2::read().as_u64()),
+        SECURITY_EXCEPTION => report!("error code {:#x}", frame.error_code),
+        _ => {}
+    }
+    report!("{}", frame);
+    report!("backtrace:\n{}", Backtrace::from_frame(frame.rip, frame.rbp));
+}
+
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// non-maskable interrupts seen since boot
+pub fn nmi_count() -> u64 {
+    NMI_COUNT.load(Ordering::Relaxed)
+}
+
+// an NMI may land in the middle of anything, even the backtrace walker,
+// so it is only counted and noted in a line
+fn nmi(frame: &ExceptionFrame) {
+    let count = NMI_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    report!("NMI #{} at {:#x}", count, frame.rip);
+}
+
+fn create_page(frame: PhysFrame) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::{
+        structures::paging::{Page, FrameDeallocator, Mapper},
+    };
+
+    let virtual_address = Cr2::read();
+    let page = Page::containing_address(virtual_address);
+    let flags = data_page_flags();
+
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = PAGE_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let map_to_result = unsafe {
+        mapper.as_mut().unwrap().map_to(page, frame, flags, frame_allocator)
+    };
+    match map_to_result {
+        Ok(flush) => flush.flush(),
+        Err(e) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// whether the fault was resolved and the access can be retried
+fn handle_page_fault(frame: &ExceptionFrame) -> bool {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    // a write to a present page may just need its own copy
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION
+                           | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && address_space::handle_cow_fault(Cr2::read())
+    {
+        return true;
+    }
+
+    // when access is permitted and not instruction fetch
+    if !error_code.intersects(PageFaultErrorCode::INSTRUCTION_FETCH
+                              | PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        // we don't need to specify flags as
+        // user part will take care of it.
+        let frame = match memory::try_allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                oom::report(oom::OutOfMemory::Frames);
+                // a task finishes the access on a reserve frame and is dropped
+                // once its poll returns, anywhere else fatal() decides what dies
+                let reserve = if task::in_task() { oom::take_reserve_frame() } else { None };
+                match reserve {
+                    Some(frame) => {
+                        task::kill_current();
+                        frame
+                    }
+                    None => return false,
+                }
+            }
+        };
+        return create_page(frame).is_ok();
+    }
+    false
+}
+
+fn fatal(frame: &ExceptionFrame) -> ! {
+    report(frame);
+    if frame.is_user() {
+        // there are no user tasks to kill yet
+        panic!("EXCEPTION: {} in user mode, nothing to kill", frame.name());
+    }
+    panic!("EXCEPTION: {}", frame.name());
+}
+
+#[no_mangle]
+extern "C" fn sos_exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector as u8 {
+        BREAKPOINT | DEBUG if gdb::handle_exception(frame) => {}
+        // traps, execution goes on after them
+        BREAKPOINT | DEBUG => report(frame),
+        NMI => nmi(frame),
+        PAGE_FAULT if handle_page_fault(frame) => {}
+        _ => fatal(frame),
+    }
+}
+
+macro_rules! stub {
+    ($name:ident) => {
+        core::mem::transmute($name as unsafe extern "C" fn())
+    };
+}
+
+/// route every architectural exception through the register saving stubs
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_fn(stub!(sos_exception_0));
+        idt.debug.set_handler_fn(stub!(sos_exception_1));
+        idt.non_maskable_interrupt.set_handler_fn(stub!(sos_exception_2))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_fn(stub!(sos_exception_3));
+        idt.overflow.set_handler_fn(stub!(sos_exception_4));
+        idt.bound_range_exceeded.set_handler_fn(stub!(sos_exception_5));
+        idt.invalid_opcode.set_handler_fn(stub!(sos_exception_6));
+        idt.device_not_available.set_handler_fn(stub!(sos_exception_7));
+        idt.double_fault.set_handler_fn(stub!(sos_exception_8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        // only a 386 with an external FPU raises it, but it is still vector 9
+        idt.coprocessor_segment_overrun.set_handler_fn(stub!(sos_exception_9));
+        idt.invalid_tss.set_handler_fn(stub!(sos_exception_10));
+        idt.segment_not_present.set_handler_fn(stub!(sos_exception_11));
+        idt.stack_segment_fault.set_handler_fn(stub!(sos_exception_12));
+        idt.general_protection_fault.set_handler_fn(stub!(sos_exception_13));
+        idt.page_fault.set_handler_fn(stub!(sos_exception_14));
+        idt.x87_floating_point.set_handler_fn(stub!(sos_exception_16));
+        idt.alignment_check.set_handler_fn(stub!(sos_exception_17));
+        idt.machine_check.set_handler_fn(stub!(sos_exception_18))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_fn(stub!(sos_exception_19));
+        idt.virtualization.set_handler_fn(stub!(sos_exception_20));
+        idt.security_exception.set_handler_fn(stub!(sos_exception_30));
+        // control protection (#CP, 21) would need a stub too, but x86_64 0.12
+        // keeps vector 21 in a private reserved field and panics when it is
+        // indexed, so it stays empty until the crate is updated
+    }
+}
+
+#[test_case]
+fn test_selector_error() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", SelectorError(0x18)), "GDT index 3");
+    assert_eq!(format!("{}", SelectorError(0x10b)), "IDT index 33, raised by an external event");
+}
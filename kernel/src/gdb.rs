@@ -0,0 +1,577 @@
+use crate::{
+    driver::serial::Serial,
+    exceptions::{ExceptionFrame, BREAKPOINT},
+    irq::{self, HandlerId, IrqError, IrqReturn, Sharing},
+    klogln,
+    memory::{inspect, phys_to_virt},
+};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+/// COM2, which QEMU gives to the second `-serial`
+pub const DEFAULT_PORT: u16 = 0x2f8;
+
+// 0x1000 as gdb is told in qSupported
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+// what gdb sends when ^C is pressed
+const BREAK: u8 = 0x03;
+const TRAP_FLAG: u64 = 1 << 8;
+// rax to r15, rip, eflags and the six segment registers
+const REGISTERS: usize = 24;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    saved: u8,
+}
+
+// the int3 gdb had written into memory, with what they replaced
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+struct Stub {
+    uart: Option<Serial>,
+    // gdb resumed the kernel and waits to hear why it stopped
+    waiting: bool,
+    breakpoints: Breakpoints,
+    input: [u8; PACKET_SIZE],
+    output: [u8; PACKET_SIZE],
+}
+
+// taken with interrupts disabled, by the stub and the UART interrupt
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    uart: None,
+    waiting: false,
+    breakpoints: Breakpoints([None; MAX_BREAKPOINTS]),
+    input: [0; PACKET_SIZE],
+    output: [0; PACKET_SIZE],
+});
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// a fault in the stub must not enter it again
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+// why the next trap stops, told to gdb
+static SIGNAL: AtomicU8 = AtomicU8::new(SIGTRAP);
+// gdb started talking while the kernel was running, its first packet is lost
+static ATTACHING: AtomicBool = AtomicBool::new(false);
+
+// COM1 and COM3 share IRQ4, COM2 and COM4 IRQ3
+fn uart_irq(base: u16) -> u8 {
+    match base {
+        0x3f8 | 0x3e8 => 4,
+        _ => 3,
+    }
+}
+
+/// listen for gdb on the UART at `base`, which nothing else may use
+pub fn init(base: u16) -> Result<HandlerId, IrqError> {
+    let mut uart = unsafe { Serial::new(base) };
+    uart.init(3);
+    without_interrupts(|| STUB.lock().uart = Some(uart));
+    let id = irq::register_irq(uart_irq(base), "gdb", Sharing::Shared, break_interrupt)?;
+    ENABLED.store(true, Ordering::Relaxed);
+    klogln!("gdb: stub on the UART at {:#x}", base);
+    Ok(id)
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn stop(signal: u8) {
+    if is_enabled() {
+        SIGNAL.store(signal, Ordering::Relaxed);
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// stop in gdb, if the stub is listening
+pub fn breakpoint() {
+    stop(SIGTRAP);
+}
+
+/// stop in gdb after a panic was printed
+pub fn on_panic() {
+    stop(SIGABRT);
+}
+
+// gdb only talks to a running kernel to stop it, with ^C or by attaching
+fn break_interrupt() -> IrqReturn {
+    let (received, interrupted, attaching) = {
+        let mut stub = STUB.lock();
+        let uart = match stub.uart.as_mut() {
+            Some(uart) => uart,
+            None => return IrqReturn::NotMine,
+        };
+        let mut received = false;
+        let mut interrupted = false;
+        let mut attaching = false;
+        while let Some(byte) = uart.try_receive() {
+            received = true;
+            interrupted |= byte == BREAK;
+            attaching |= byte == b'$';
+        }
+        (received, interrupted, attaching)
+    };
+    if interrupted {
+        stop(SIGINT);
+    } else if attaching {
+        ATTACHING.store(true, Ordering::Relaxed);
+        stop(SIGTRAP);
+    }
+    if received { IrqReturn::Handled } else { IrqReturn::NotMine }
+}
+
+/// talk to gdb until it resumes, for a breakpoint or a debug exception.
+/// False if the stub is not listening.
+pub(crate) fn handle_exception(frame: &mut ExceptionFrame) -> bool {
+    if !is_enabled() || ACTIVE.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    let mut stub = match STUB.try_lock() {
+        Some(stub) => stub,
+        None => {
+            ACTIVE.store(false, Ordering::Release);
+            return false;
+        }
+    };
+    frame.rflags &= !TRAP_FLAG;
+    // stopped right after one of our int3, gdb wants to see it at the breakpoint
+    if frame.vector as u8 == BREAKPOINT && stub.breakpoints.find(frame.rip.wrapping_sub(1)).is_some() {
+        frame.rip -= 1;
+    }
+    let signal = SIGNAL.swap(SIGTRAP, Ordering::Relaxed);
+    if ATTACHING.swap(false, Ordering::Relaxed) {
+        // gdb sends it again on a NAK
+        stub.uart().send(b'-');
+    }
+    stub.session(frame, signal);
+    drop(stub);
+    ACTIVE.store(false, Ordering::Release);
+    true
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn parse_hex(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return None;
+    }
+    bytes.iter().try_fold(0, |value, &byte| Some(value << 4 | hex_digit(byte)? as u64))
+}
+
+fn parse_byte(bytes: &[u8]) -> Option<u8> {
+    Some(hex_digit(*bytes.get(0)?)? << 4 | hex_digit(*bytes.get(1)?)?)
+}
+
+// a register as gdb sends it, the bytes in memory order
+fn parse_le(bytes: &[u8], size: usize) -> Option<u64> {
+    (0..size).try_fold(0, |value, index| {
+        Some(value | (parse_byte(bytes.get(index * 2..)?)? as u64) << (index * 8))
+    })
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..position], &bytes[position + 1..]))
+}
+
+fn read_byte(address: u64) -> Option<u8> {
+    let phys = inspect::translate(VirtAddr::try_new(address).ok()?)?;
+    Some(unsafe { *phys_to_virt(phys).as_ptr::<u8>() })
+}
+
+// through the physical memory window, which is writable where the kernel text is not
+fn write_byte(address: u64, value: u8) -> Option<()> {
+    let phys = inspect::translate(VirtAddr::try_new(address).ok()?)?;
+    unsafe { *phys_to_virt(phys).as_mut_ptr::<u8>() = value };
+    Some(())
+}
+
+// the value and size of register `n` in the order of gdb's amd64 target
+fn read_register(frame: &ExceptionFrame, n: usize) -> Option<(u64, usize)> {
+    let value = match n {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => return Some((frame.rflags, 4)),
+        18 => return Some((frame.cs, 4)),
+        19 => return Some((frame.ss, 4)),
+        // ds, es, fs and gs are flat in long mode
+        20..=23 => return Some((0, 4)),
+        _ => return None,
+    };
+    Some((value, 8))
+}
+
+// the segment registers are left alone, a wrong one would fault at iretq
+fn write_register(frame: &mut ExceptionFrame, n: usize, value: u64) -> Option<()> {
+    let register = match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18..=23 => return Some(()),
+        _ => return None,
+    };
+    *register = value;
+    Some(())
+}
+
+/// a packet being put together
+struct Reply<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Reply<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len < self.buffer.len() {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            }
+        }
+    }
+
+    fn hex(&mut self, byte: u8) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        self.bytes(&[DIGITS[byte as usize >> 4], DIGITS[byte as usize & 0xf]]);
+    }
+
+    fn hex_le(&mut self, value: u64, size: usize) {
+        for index in 0..size {
+            self.hex((value >> (index * 8)) as u8);
+        }
+    }
+
+    fn ok(&mut self) {
+        self.bytes(b"OK");
+    }
+
+    fn error(&mut self) {
+        self.bytes(b"E01");
+    }
+
+    fn result(&mut self, result: Option<()>) {
+        match result {
+            Some(()) => self.ok(),
+            None => self.error(),
+        }
+    }
+}
+
+/// what a packet leaves the kernel to do
+enum Resume {
+    Stay,
+    Continue,
+    Step,
+    /// continue after the reply, if any, gdb is gone
+    Detach,
+}
+
+impl Breakpoints {
+    fn find(&self, address: u64) -> Option<usize> {
+        self.0.iter().position(|slot| slot.map_or(false, |b| b.address == address))
+    }
+
+    fn insert(&mut self, address: u64) -> Option<()> {
+        if self.find(address).is_some() {
+            return Some(());
+        }
+        let slot = self.0.iter().position(Option::is_none)?;
+        let saved = read_byte(address)?;
+        write_byte(address, INT3)?;
+        self.0[slot] = Some(Breakpoint { address, saved });
+        Some(())
+    }
+
+    fn remove(&mut self, address: u64) -> Option<()> {
+        let slot = self.find(address)?;
+        let breakpoint = self.0[slot].take()?;
+        write_byte(breakpoint.address, breakpoint.saved)
+    }
+
+    fn clear(&mut self) {
+        for slot in self.0.iter_mut() {
+            if let Some(breakpoint) = slot.take() {
+                write_byte(breakpoint.address, breakpoint.saved);
+            }
+        }
+    }
+}
+
+// `addr,len` of the m and M packets
+fn parse_range(bytes: &[u8]) -> Option<(u64, usize)> {
+    let (address, len) = split(bytes, b',')?;
+    Some((parse_hex(address)?, parse_hex(len)? as usize))
+}
+
+fn command(packet: &[u8], frame: &mut ExceptionFrame, signal: u8,
+           breakpoints: &mut Breakpoints, reply: &mut Reply) -> Resume {
+    let (&kind, arguments) = match packet.split_first() {
+        Some(split) => split,
+        None => return Resume::Stay,
+    };
+    match kind {
+        b'?' => {
+            reply.bytes(b"S");
+            reply.hex(signal);
+        }
+        b'g' => {
+            for n in 0..REGISTERS {
+                let (value, size) = read_register(frame, n).unwrap();
+                reply.hex_le(value, size);
+            }
+        }
+        b'G' => {
+            // gdb may send more than the registers in `g`, they are left alone
+            let mut rest = arguments;
+            for n in 0..REGISTERS {
+                let (_, size) = read_register(frame, n).unwrap();
+                let value = match parse_le(rest, size) {
+                    Some(value) => value,
+                    None => break,
+                };
+                write_register(frame, n, value);
+                rest = &rest[size * 2..];
+            }
+            reply.ok();
+        }
+        b'p' => match parse_hex(arguments).and_then(|n| read_register(frame, n as usize)) {
+            Some((value, size)) => reply.hex_le(value, size),
+            None => reply.error(),
+        },
+        b'P' => {
+            let result = split(arguments, b'=').and_then(|(n, value)| {
+                let n = parse_hex(n)? as usize;
+                let (_, size) = read_register(frame, n)?;
+                write_register(frame, n, parse_le(value, size)?)
+            });
+            reply.result(result);
+        }
+        b'm' => {
+            // two hex digits for every byte, and room for the checksum
+            match parse_range(arguments).filter(|&(_, len)| len <= (PACKET_SIZE - 4) / 2) {
+                Some((address, len)) => {
+                    // as much as can be read, an error only if that is nothing
+                    let mut read = 0;
+                    while read < len {
+                        // the end of the address space stops a read like an unmapped page
+                        match address.checked_add(read as u64).and_then(read_byte) {
+                            Some(byte) => reply.hex(byte),
+                            None => break,
+                        }
+                        read += 1;
+                    }
+                    if read == 0 && len != 0 {
+                        reply.error();
+                    }
+                }
+                None => reply.error(),
+            }
+        }
+        b'M' => {
+            let result = split(arguments, b':').and_then(|(range, data)| {
+                let (address, len) = parse_range(range)?;
+                for offset in 0..len {
+                    let byte = parse_byte(data.get(offset * 2..)?)?;
+                    write_byte(address.checked_add(offset as u64)?, byte)?;
+                }
+                Some(())
+            });
+            reply.result(result);
+        }
+        b'c' | b's' => {
+            if let Some(address) = parse_hex(arguments) {
+                frame.rip = address;
+            }
+            return if kind == b'c' { Resume::Continue } else { Resume::Step };
+        }
+        b'Z' | b'z' => {
+            // only software breakpoints, `0,addr,kind`
+            let address = match split(arguments, b',') {
+                Some((b"0", rest)) => split(rest, b',').and_then(|(address, _)| parse_hex(address)),
+                _ => return Resume::Stay,
+            };
+            let result = address.and_then(|address| if kind == b'Z' {
+                breakpoints.insert(address)
+            } else {
+                breakpoints.remove(address)
+            });
+            reply.result(result);
+        }
+        b'D' => {
+            breakpoints.clear();
+            reply.ok();
+            return Resume::Detach;
+        }
+        b'k' => {
+            breakpoints.clear();
+            return Resume::Detach;
+        }
+        b'H' | b'T' => reply.ok(),
+        b'q' if arguments.starts_with(b"Supported") => reply.bytes(b"PacketSize=1000"),
+        b'q' if arguments == b"Attached" => reply.bytes(b"1"),
+        // an empty reply tells gdb the packet is not supported
+        _ => {}
+    }
+    Resume::Stay
+}
+
+impl Stub {
+    fn uart(&mut self) -> &mut Serial {
+        self.uart.as_mut().unwrap()
+    }
+
+    // the payload of the next packet with a good checksum, acknowledged
+    fn receive_packet(&mut self) -> usize {
+        loop {
+            // acks and a late ^C may come before it
+            while self.uart().receive() != b'$' {}
+            let mut len = 0;
+            let mut sum = 0u8;
+            loop {
+                let byte = self.uart().receive();
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                if len < PACKET_SIZE {
+                    self.input[len] = byte;
+                    len += 1;
+                }
+            }
+            let checksum = [self.uart().receive(), self.uart().receive()];
+            if parse_byte(&checksum) == Some(sum) {
+                self.uart().send(b'+');
+                return len;
+            }
+            self.uart().send(b'-');
+        }
+    }
+
+    // sends the first `len` bytes of the output, until gdb acknowledges them
+    fn send_packet(&mut self, len: usize) {
+        let sum = self.output[..len].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let mut checksum = [0; 2];
+        Reply { buffer: &mut checksum, len: 0 }.hex(sum);
+        let Stub { uart, output, .. } = &mut *self;
+        let uart = uart.as_mut().unwrap();
+        loop {
+            uart.send(b'$');
+            for &byte in output[..len].iter() {
+                uart.send(byte);
+            }
+            uart.send(b'#');
+            uart.send(checksum[0]);
+            uart.send(checksum[1]);
+            if uart.receive() != b'-' {
+                break;
+            }
+        }
+    }
+
+    fn session(&mut self, frame: &mut ExceptionFrame, signal: u8) {
+        // a stop reply if gdb waits for one, as if it had asked
+        let mut packet = if self.waiting { None } else { Some(self.receive_packet()) };
+        self.waiting = false;
+        loop {
+            let Stub { input, output, breakpoints, .. } = &mut *self;
+            let packet: &[u8] = match packet {
+                Some(len) => &input[..len],
+                None => b"?",
+            };
+            let mut reply = Reply { buffer: output, len: 0 };
+            let resume = command(packet, frame, signal, breakpoints, &mut reply);
+            let len = reply.len;
+            match resume {
+                Resume::Stay => self.send_packet(len),
+                Resume::Continue => {
+                    self.waiting = true;
+                    return;
+                }
+                Resume::Step => {
+                    frame.rflags |= TRAP_FLAG;
+                    self.waiting = true;
+                    return;
+                }
+                Resume::Detach => {
+                    if len != 0 {
+                        self.send_packet(len);
+                    }
+                    return;
+                }
+            }
+            packet = Some(self.receive_packet());
+        }
+    }
+}
+
+#[test_case]
+fn test_command() {
+    let mut frame = ExceptionFrame {
+        r15: 0, r14: 0, r13: 0, r12: 0, r11: 0, r10: 0, r9: 0, r8: 0,
+        rbp: 0, rdi: 0, rsi: 0, rdx: 0, rcx: 0, rbx: 0, rax: 0x1122,
+        vector: BREAKPOINT as u64, error_code: 0,
+        rip: 0x1000, cs: 8, rflags: 0x202, rsp: 0, ss: 0,
+    };
+    let mut breakpoints = Breakpoints([None; MAX_BREAKPOINTS]);
+    let mut buffer = [0; 64];
+    let mut run = |packet: &[u8], frame: &mut ExceptionFrame| {
+        let mut reply = Reply { buffer: &mut buffer, len: 0 };
+        command(packet, frame, SIGTRAP, &mut breakpoints, &mut reply);
+        let len = reply.len;
+        alloc::vec::Vec::from(&buffer[..len])
+    };
+
+    assert_eq!(run(b"?", &mut frame), b"S05");
+    assert_eq!(run(b"p0", &mut frame), b"2211000000000000");
+    assert_eq!(run(b"P10=0020000000000000", &mut frame), b"OK");
+    assert_eq!(frame.rip, 0x2000);
+    assert_eq!(run(b"p11", &mut frame), b"02020000");
+    assert_eq!(run(b"vMustReplyEmpty", &mut frame), b"");
+
+    let value = 0x5au8;
+    let address = &value as *const u8 as u64;
+    let packet = alloc::format!("m{:x},1", address);
+    assert_eq!(run(packet.as_bytes(), &mut frame), b"5a");
+    // ranges running past the end of the address space
+    assert_eq!(run(b"mffffffffffffffff,2", &mut frame), b"E01");
+    assert_eq!(run(b"Mffffffffffffffff,2:0000", &mut frame), b"E01");
+}
@@ -4,18 +4,29 @@ use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector
 use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// NMIs and machine checks can arrive anywhere, even right after a syscall
+// entry or with a broken stack, so they get a known good one too
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+// every use is a stack of its own
+macro_rules! ist_stack {
+    () => {{
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + STACK_SIZE;
+        stack_end
+    }};
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack!();
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack!();
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack!();
         tss
     };
 }
@@ -0,0 +1,91 @@
+use crate::memory::{inspect, phys_to_virt};
+use crate::klogln;
+use core::arch::x86_64::__cpuid_count;
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{PageTable, PageTableFlags as Flags, PhysFrame},
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Protections {
+    pub nxe: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
+}
+
+fn supported() -> Protections {
+    let (leaf_7, extended) = unsafe { (__cpuid_count(7, 0), __cpuid_count(0x8000_0001, 0)) };
+    Protections {
+        nxe: extended.edx & (1 << 20) != 0,
+        smep: leaf_7.ebx & (1 << 7) != 0,
+        smap: leaf_7.ebx & (1 << 20) != 0,
+        umip: leaf_7.ecx & (1 << 2) != 0,
+    }
+}
+
+/// turn on every protection feature the CPU has
+pub fn enable_protections() -> Protections {
+    let supported = supported();
+    unsafe {
+        if supported.nxe {
+            Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        Cr4::update(|f| {
+            f.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, supported.smep);
+            f.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, supported.smap);
+            f.set(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION, supported.umip);
+        });
+        // read only pages must stay read only for the kernel too
+        Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    supported
+}
+
+fn strip_execute(frame: PhysFrame, level: u8, inherited_writable: bool) -> usize {
+    let table: &mut PageTable = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr() };
+    let mut stripped = 0;
+    for entry in table.iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(Flags::PRESENT) || flags.contains(Flags::NO_EXECUTE) {
+            continue;
+        }
+
+        let writable = inherited_writable && flags.contains(Flags::WRITABLE);
+        if level == 1 || flags.contains(Flags::HUGE_PAGE) {
+            if writable {
+                entry.set_flags(flags | Flags::NO_EXECUTE);
+                stripped += 1;
+            }
+        } else {
+            let next = PhysFrame::containing_address(entry.addr());
+            stripped += strip_execute(next, level - 1, writable);
+        }
+    }
+    stripped
+}
+
+/// enable the CPU protections and make every writable mapping of the
+/// active page table non-executable.
+pub fn init() {
+    let protections = enable_protections();
+    klogln!("hardening: {:?}", protections);
+
+    if protections.nxe {
+        let stripped = strip_execute(inspect::active_root(), 4, true);
+        tlb::flush_all();
+        klogln!("hardening: made {} writable mappings non-executable", stripped);
+    }
+
+    #[cfg(feature = "wx-selftest")]
+    {
+        let violations = inspect::audit(inspect::active_root());
+        for violation in violations.iter() {
+            if let inspect::Violation::WritableExecutable(mapping) = violation {
+                panic!("W^X self-test failed: {}", mapping);
+            }
+        }
+    }
+}
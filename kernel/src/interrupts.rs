@@ -1,34 +1,21 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use pic8259_simple::ChainedPics;
+use x86_64::structures::idt::InterruptDescriptorTable;
 use spin;
 use lazy_static::lazy_static;
-use crate::{println, print, gdt, hlt_loop, driver::serial::COM1, memory::{PAGE_ALLOCATOR, MAPPER}};
-use x86_64::registers::control::Cr2;
+use crate::{klogln, exceptions, irq, driver::{apic, pic::ChainedPics}};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.divide_error.set_handler_fn(divided_by_zero_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
-        idt[InterruptIndex::COM1.as_usize()]
-            .set_handler_fn(com1_interrupt_handler);
+        exceptions::install(&mut idt);
+        irq::install(&mut idt);
         idt
     };
 }
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
-// TODO: WRITE MY OWN PICS
+// the slave PIC hangs off this line of the master
+const CASCADE_IRQ: u8 = 2;
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -37,128 +24,61 @@ pub fn init_idt() {
     IDT.load();
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-    COM1 = PIC_1_OFFSET + 4,
-}
+/// move the legacy interrupts from the PIC to the APIC, if there is one
+pub fn init_apic(topology: apic::Topology) {
+    use x86_64::instructions::interrupts::without_interrupts;
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+    if !apic::supported().0 {
+        klogln!("apic: not supported, staying on the PIC");
+        return;
     }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    if let Err(e) = apic::init(topology) {
+        klogln!("apic: {:?}, staying on the PIC", e);
+        return;
     }
-}
-
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: &mut InterruptStackFrame)
-{
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame, _error_code: u64) -> !
-{
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn divided_by_zero_handler(
-    stack_frame: &mut InterruptStackFrame)
-{
-    println!("EXCEPTION: DIVIDED BY ZERO\n{:#?}", stack_frame);
-}
-
-fn create_page() -> bool {
-    use x86_64::{
-        structures::paging::{Page, PageTableFlags as Flags, FrameAllocator, Mapper},
-    };
-
-    let virtual_address = Cr2::read();
-    let page = Page::containing_address(virtual_address);
-    let flags = Flags::PRESENT | Flags::WRITABLE;
-    let frame = match (*PAGE_ALLOCATOR.lock()).as_mut().unwrap().allocate_frame()
-    {
-        Some(frame) => frame,
-        None => return false,
-    };
 
-    let map_to_result = unsafe {
-        (*MAPPER.lock()).as_mut().unwrap().
-            map_to(page, frame, flags, (*PAGE_ALLOCATOR.lock()).as_mut().unwrap())
-    };
-    map_to_result.expect("map_to failed").flush();
-    true
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode)
-{
-    if (error_code & (PageFaultErrorCode::INSTRUCTION_FETCH
-                    | PageFaultErrorCode::PROTECTION_VIOLATION))
-        == PageFaultErrorCode::from_bits(0 as u64).unwrap()
-    // when access is permitted and not instruction fetch
-    {
-        // we don't need to specify flags as
-        // user part will take care of it.
-        if create_page() {
-            return ;
+    without_interrupts(|| {
+        // the lines open at the PIC stay open at the IOAPIC
+        let mut pics = PICS.lock();
+        let mask = pics.mask();
+        unsafe { pics.disable() };
+        let mut apic = apic::APIC.lock();
+        let apic = apic.as_mut().unwrap();
+        for irq in (0..16).filter(|&irq| irq != CASCADE_IRQ && mask & 1 << irq == 0) {
+            if !apic.route_isa_irq(irq, irq::irq_vector(irq)) {
+                klogln!("apic: no IOAPIC for IRQ {}", irq);
+            }
         }
-    }
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+        klogln!("apic: local APIC {} in {} mode, {} IOAPICs",
+                apic.local.id(), if apic.local.is_x2apic() { "x2APIC" } else { "xAPIC" },
+                apic.io_apics.len());
+    });
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame)
-{
-    // print!(".");
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// acknowledge `vector` at whichever controller delivered it, if any did.
+/// `int n` is delivered by none of them.
+pub fn end_of_vector(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt(vector);
+    } else if irq::vector_irq(vector).is_some() {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame)
-{
-    use x86_64::instructions::port::Port;
-
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
 }
 
-extern "x86-interrupt" fn com1_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame)
-{
-    let data = COM1.lock().receive();
-
-    // TODO: SET A BUFFER FOR COMMANDS
-
-    match data {
-        0x0A | 0x0D => println!(),
-        0x20..0x7F => print!("{}", data as char),
-        _ => (),
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::COM1.as_u8());
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
@@ -0,0 +1,386 @@
+use crate::{
+    driver::apic,
+    interrupts::{self, PICS, PIC_1_OFFSET},
+    klogln,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{HandlerFunc, InterruptDescriptorTable},
+};
+
+/// the first vector after the exceptions
+pub const FIRST_VECTOR: u8 = 32;
+const VECTORS: usize = 256 - FIRST_VECTOR as usize;
+// legacy lines of the PICs, or of the IOAPIC for ISA devices
+const ISA_IRQS: u8 = 16;
+// each stub is padded to this many bytes
+const STUB_SIZE: usize = 16;
+
+// every stub pushes its vector and hands it to `sos_irq_dispatch`,
+// saving only what the C ABI does not keep
+global_asm!(r#"
+.balign 16
+.global sos_irq_stubs
+sos_irq_stubs:
+.set sos_irq_vector, 32
+.rept 224
+    .balign 16
+    pushq $sos_irq_vector
+    jmp sos_irq_common
+    .set sos_irq_vector, sos_irq_vector + 1
+.endr
+
+.intel_syntax noprefix
+sos_irq_common:
+    push rax
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    mov rdi, [rsp + 72]
+    cld
+    sub rsp, 8
+    call sos_irq_dispatch
+    add rsp, 8
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rax
+    add rsp, 8
+    iretq
+.att_syntax
+"#);
+
+extern "C" {
+    fn sos_irq_stubs();
+}
+
+/// what a handler says about an interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    /// the device of this handler did not raise it, for shared lines
+    NotMine,
+}
+
+/// whether other handlers may be on the same vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharing {
+    Exclusive,
+    Shared,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// an exception, or an IRQ beyond the ISA lines
+    InvalidVector(u8),
+    /// someone holds the vector exclusively, or wants to
+    Busy(u8),
+    NotRegistered,
+    /// the IOAPICs have no pin for the line
+    NoRoute(u8),
+}
+
+/// what `unregister` takes back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+struct Action {
+    id: u64,
+    name: &'static str,
+    sharing: Sharing,
+    handled: u64,
+    handler: Box<dyn FnMut() -> IrqReturn + Send>,
+}
+
+const NO_ACTIONS: Vec<Action> = Vec::new();
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+// taken with interrupts disabled outside of the interrupts. Handlers run
+// with it held and must not register or unregister.
+static ACTIONS: Mutex<[Vec<Action>; VECTORS]> = Mutex::new([NO_ACTIONS; VECTORS]);
+static COUNTS: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+static UNHANDLED: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// the vector ISA `irq` arrives on, from the PIC or the IOAPIC
+pub fn irq_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// the ISA line `vector` belongs to, if any
+pub fn vector_irq(vector: u8) -> Option<u8> {
+    vector.checked_sub(PIC_1_OFFSET).filter(|&irq| irq < ISA_IRQS)
+}
+
+fn index(vector: u8) -> Result<usize, IrqError> {
+    vector.checked_sub(FIRST_VECTOR).map(usize::from).ok_or(IrqError::InvalidVector(vector))
+}
+
+/// point vectors 32 to 255 at the dispatcher
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    for index in 0..VECTORS {
+        let stub = sos_irq_stubs as usize + index * STUB_SIZE;
+        let handler = unsafe { core::mem::transmute::<usize, HandlerFunc>(stub) };
+        idt[FIRST_VECTOR as usize + index].set_handler_fn(handler);
+    }
+}
+
+/// call `handler` whenever `vector` arrives. The line behind it is left
+/// as it is, see `register_irq`.
+pub fn register_vector<F>(vector: u8, name: &'static str, sharing: Sharing, handler: F)
+    -> Result<HandlerId, IrqError>
+    where F: FnMut() -> IrqReturn + Send + 'static
+{
+    let index = index(vector)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let action = Action { id, name, sharing, handled: 0, handler: Box::new(handler) };
+    without_interrupts(|| {
+        let mut actions = ACTIONS.lock();
+        let actions = &mut actions[index];
+        let exclusive = sharing == Sharing::Exclusive
+            || actions.iter().any(|action| action.sharing == Sharing::Exclusive);
+        if exclusive && !actions.is_empty() {
+            return Err((IrqError::Busy(vector), action));
+        }
+        actions.push(action);
+        Ok(())
+    })
+    // the handler may own things that must not be freed with interrupts disabled
+    .map_err(|(e, _action)| e)?;
+    Ok(HandlerId { vector, id })
+}
+
+/// call `handler` for ISA `irq` and unmask the line
+pub fn register_irq<F>(irq: u8, name: &'static str, sharing: Sharing, handler: F)
+    -> Result<HandlerId, IrqError>
+    where F: FnMut() -> IrqReturn + Send + 'static
+{
+    if irq >= ISA_IRQS {
+        return Err(IrqError::InvalidVector(irq));
+    }
+    let id = register_vector(irq_vector(irq), name, sharing, handler)?;
+    if let Err(e) = enable_irq(irq) {
+        unregister(id)?;
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// remove a handler, masking its ISA line when it was the last one there
+pub fn unregister(id: HandlerId) -> Result<(), IrqError> {
+    let index = index(id.vector)?;
+    let (action, last) = without_interrupts(|| {
+        let mut actions = ACTIONS.lock();
+        let actions = &mut actions[index];
+        let position = actions.iter().position(|action| action.id == id.id)?;
+        let action = actions.remove(position);
+        Some((action, actions.is_empty()))
+    })
+    .ok_or(IrqError::NotRegistered)?;
+    drop(action);
+    if let (true, Some(irq)) = (last, vector_irq(id.vector)) {
+        disable_irq(irq);
+    }
+    Ok(())
+}
+
+/// let ISA `irq` through to its vector, at whichever controller is in use
+pub fn enable_irq(irq: u8) -> Result<(), IrqError> {
+    without_interrupts(|| {
+        if apic::is_enabled() {
+            let mut apic = apic::APIC.lock();
+            let apic = apic.as_mut().unwrap();
+            if !apic.route_isa_irq(irq, irq_vector(irq)) {
+                return Err(IrqError::NoRoute(irq));
+            }
+        } else {
+            PICS.lock().unmask_irq(irq);
+        }
+        Ok(())
+    })
+}
+
+pub fn disable_irq(irq: u8) {
+    without_interrupts(|| {
+        if apic::is_enabled() {
+            if let Some(apic) = apic::APIC.lock().as_mut() {
+                apic.mask_isa_irq(irq);
+            }
+        } else {
+            PICS.lock().mask_irq(irq);
+        }
+    });
+}
+
+// IRQ7 and IRQ15 are used by nothing, anything arriving there is spurious
+// unless the PIC says the line really is in service. The APIC has its own
+// vector for them, which is never acknowledged.
+fn is_spurious(vector: u8) -> bool {
+    if apic::is_enabled() {
+        vector == apic::SPURIOUS_VECTOR
+    } else {
+        PICS.lock().check_spurious(vector)
+    }
+}
+
+#[no_mangle]
+extern "C" fn sos_irq_dispatch(vector: u64) {
+    let vector = vector as u8;
+    if is_spurious(vector) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let index = vector as usize - FIRST_VECTOR as usize;
+    COUNTS[index].fetch_add(1, Ordering::Relaxed);
+
+    // every handler of a shared line runs, more than one device may be waiting
+    let mut handled = false;
+    for action in ACTIONS.lock()[index].iter_mut() {
+        if (action.handler)() == IrqReturn::Handled {
+            action.handled += 1;
+            handled = true;
+        }
+    }
+    if !handled {
+        UNHANDLED[index].fetch_add(1, Ordering::Relaxed);
+    }
+    interrupts::end_of_vector(vector);
+}
+
+/// times `vector` arrived
+pub fn count(vector: u8) -> u64 {
+    index(vector).map_or(0, |index| COUNTS[index].load(Ordering::Relaxed))
+}
+
+/// times `vector` arrived and no handler took it
+pub fn unhandled(vector: u8) -> u64 {
+    index(vector).map_or(0, |index| UNHANDLED[index].load(Ordering::Relaxed))
+}
+
+pub fn spurious() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// a vector in use, with its handlers and how many they each took
+#[derive(Debug, Clone)]
+pub struct IrqStat {
+    pub vector: u8,
+    pub irq: Option<u8>,
+    pub count: u64,
+    pub unhandled: u64,
+    pub handlers: Vec<(&'static str, u64)>,
+}
+
+/// every vector with a handler or an interrupt so far, in vector order
+pub fn stats() -> Vec<IrqStat> {
+    let handlers: Vec<Vec<(&'static str, u64)>> = without_interrupts(|| {
+        ACTIONS.lock().iter()
+            .map(|actions| actions.iter().map(|action| (action.name, action.handled)).collect())
+            .collect()
+    });
+    handlers.into_iter().enumerate()
+        .map(|(index, handlers)| {
+            let vector = FIRST_VECTOR + index as u8;
+            IrqStat {
+                vector,
+                irq: vector_irq(vector),
+                count: count(vector),
+                unhandled: unhandled(vector),
+                handlers,
+            }
+        })
+        .filter(|stat| stat.count != 0 || !stat.handlers.is_empty())
+        .collect()
+}
+
+/// the interrupts seen so far, like /proc/interrupts
+pub struct Table {
+    stats: Vec<IrqStat>,
+    spurious: u64,
+}
+
+pub fn table() -> Table {
+    Table { stats: stats(), spurious: spurious() }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "VEC IRQ       COUNT   UNHANDLED  HANDLERS")?;
+        for stat in self.stats.iter() {
+            write!(f, "{:3} ", stat.vector)?;
+            match stat.irq {
+                Some(irq) => write!(f, "{:3} ", irq)?,
+                None => f.write_str("  - ")?,
+            }
+            write!(f, "{:11} {:11}  ", stat.count, stat.unhandled)?;
+            for (index, (name, handled)) in stat.handlers.iter().enumerate() {
+                if index != 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{} ({})", name, handled)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "SPU     {:11}", self.spurious)
+    }
+}
+
+pub fn dump() {
+    klogln!("{}", table());
+}
+
+#[test_case]
+fn test_register_vector() {
+    use alloc::sync::Arc;
+
+    const VECTOR: u8 = 0x80;
+    let hits = Arc::new(AtomicU64::new(0));
+    let counter = hits.clone();
+    let id = register_vector(VECTOR, "test", Sharing::Shared, move || {
+        counter.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::Handled
+    }).unwrap();
+    let other = register_vector(VECTOR, "test-other", Sharing::Shared, || IrqReturn::NotMine).unwrap();
+    assert_eq!(register_vector(VECTOR, "test-exclusive", Sharing::Exclusive, || IrqReturn::Handled),
+               Err(IrqError::Busy(VECTOR)));
+
+    let before = count(VECTOR);
+    let unhandled_before = unhandled(VECTOR);
+    unsafe { asm!("int 0x80", options(nomem, nostack)) };
+    assert_eq!(hits.load(Ordering::Relaxed), 1);
+    assert_eq!(count(VECTOR), before + 1);
+    assert!(stats().iter().any(|stat| stat.vector == VECTOR && stat.handlers.len() == 2));
+
+    unregister(id).unwrap();
+    unregister(other).unwrap();
+    assert_eq!(unregister(id), Err(IrqError::NotRegistered));
+    unsafe { asm!("int 0x80", options(nomem, nostack)) };
+    assert_eq!(hits.load(Ordering::Relaxed), 1);
+    assert_eq!(unhandled(VECTOR), unhandled_before + 1);
+}
@@ -10,16 +10,29 @@
 #![feature(alloc_error_handler)]
 #![feature(exclusive_range_pattern)]
 #![feature(wake_trait)]
+#![feature(asm)]
+#![feature(global_asm)]
 
 extern crate alloc;
 
 pub mod interrupts;
+pub mod exceptions;
+pub mod irq;
+pub mod backtrace;
+pub mod gdb;
 pub mod task;
 pub mod gdt;
 pub mod memory;
+pub mod oom;
 pub mod allocator;
 pub mod utils;
 pub mod driver;
+pub mod hardening;
+pub mod log;
+pub mod acpi;
+pub mod power;
+pub mod smbios;
+pub mod time;
 
 use core::panic::PanicInfo;
 use memory::{ BootInfoFrameAllocator, PAGE_ALLOCATOR, MAPPER };
@@ -28,6 +41,9 @@ use x86_64::VirtAddr;
 use bootloader::BootInfo;
 
 pub fn init(boot_info: &'static BootInfo) {
+    // nothing before this may allocate, only klog! works this early
+    memory::memblock::init(&boot_info.memory_map, VirtAddr::new(boot_info.physical_memory_offset));
+    klogln!("sos: {} KiB of usable memory", memory::memblock::usable_memory() / 1024);
     gdt::init();
 
     unsafe {
@@ -35,16 +51,13 @@ pub fn init(boot_info: &'static BootInfo) {
         let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
         *MAPPER.lock() = Some(memory::init(phys_mem_offset));
     }
+    memory::mmio::init_pat();
+    memory::address_space::init();
+    memory::dma::init(PAGE_ALLOCATOR.lock().as_ref().unwrap().dma_zone());
     interrupts::init_idt();
 
-    unsafe {
-        use x86_64::instructions::port::Port;
-
-        let mut port = Port::new(0x21);
-        port.write(0 as u8); // enable all interrupts on pics
-
-        interrupts::PICS.lock().initialize()
-    };
+    // every line starts masked, drivers open theirs with irq::register_irq
+    unsafe { interrupts::PICS.lock().initialize() };
 
     // we must call it before interrupts as
     // it may cause deadlock otherwise.
@@ -52,13 +65,46 @@ pub fn init(boot_info: &'static BootInfo) {
     allocator::init_heap(MAPPER.lock().as_mut().unwrap(),
                          PAGE_ALLOCATOR.lock().as_mut().unwrap())
         .expect("heap allocation failed");
+    log::init();
+    backtrace::init(&boot_info.memory_map);
+    // BIOS scan only: the bootloader crate's BootInfo carries no RSDP. The
+    // UEFI loader in boot/ finds one, but its BootInfo is not what this
+    // kernel is entered with, so UEFI only machines without a BIOS area
+    // copy of the RSDP boot without ACPI.
+    let topology = match acpi::init(None) {
+        Ok(acpi) => {
+            klogln!("acpi: revision {} by {}, tables {:?}", acpi.revision, acpi.oem_id(),
+                    acpi.tables.iter().map(|sdt| sdt.signature).collect::<alloc::vec::Vec<_>>());
+            acpi.madt.as_ref().map(acpi::Madt::topology)
+        }
+        Err(e) => {
+            klogln!("acpi: {:?}", e);
+            None
+        }
+    };
+    interrupts::init_apic(topology.unwrap_or_else(driver::apic::Topology::legacy));
+    // BIOS scan only, for the same reason as the RSDP above
+    if let Err(e) = smbios::init(None) {
+        klogln!("smbios: {:?}", e);
+    }
+    time::init(time::DEFAULT_HZ);
+    time::init_wall_clock();
+    driver::rtc::init().expect("RTC interrupt taken");
+    task::keyboard::init().expect("keyboard interrupt taken");
+    driver::serial::enable_echo().expect("COM1 interrupt taken");
+    #[cfg(feature = "gdb")]
+    gdb::init(gdb::DEFAULT_PORT).expect("gdb UART interrupt taken");
+    log::enable_timestamps();
+
+    oom::init();
+    hardening::init();
 
     x86_64::instructions::interrupts::enable();
 }
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    oom::out_of_memory(oom::OutOfMemory::Heap(layout))
 }
 
 #[cfg(test)]
@@ -93,6 +139,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
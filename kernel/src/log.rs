@@ -0,0 +1,261 @@
+use crate::{
+    driver::serial::{Serial, COM1},
+    time::SystemTime,
+};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+const EARLY_LOG_SIZE: usize = 4096;
+pub const LOG_SIZE: usize = 64 * 1024;
+// a VecDeque keeps one slot of its power of two buffer free,
+// so this many bytes take exactly LOG_SIZE of the heap
+const LOG_CAPACITY: usize = LOG_SIZE - 1;
+
+// polled directly, so it works before the heap and any lazy_static device
+static EARLY_SERIAL: Mutex<Serial> = Mutex::new(unsafe { Serial::new(0x3f8) });
+static EARLY_SERIAL_READY: AtomicBool = AtomicBool::new(false);
+
+fn lock<T>(mutex: &'static Mutex<T>, blocking: bool) -> Option<MutexGuard<'static, T>> {
+    if blocking {
+        Some(mutex.lock())
+    } else {
+        mutex.try_lock()
+    }
+}
+
+fn early_serial(blocking: bool) -> Option<MutexGuard<'static, Serial>> {
+    let mut serial = lock(&EARLY_SERIAL, blocking)?;
+    if !EARLY_SERIAL_READY.swap(true, Ordering::Relaxed) {
+        serial.init(3);
+    }
+    Some(serial)
+}
+
+// what was logged before the heap existed, replayed by `init`
+struct EarlyLog {
+    buffer: [u8; EARLY_LOG_SIZE],
+    len: usize,
+    dropped: usize,
+}
+
+impl Write for EarlyLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(EARLY_LOG_SIZE - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        self.dropped += s.len() - len;
+        Ok(())
+    }
+}
+
+static EARLY_LOG: Mutex<EarlyLog> = Mutex::new(EarlyLog {
+    buffer: [0; EARLY_LOG_SIZE],
+    len: 0,
+    dropped: 0,
+});
+
+// the newest LOG_CAPACITY bytes of the kernel log
+struct KernelLog {
+    buffer: VecDeque<u8>,
+    dropped: usize,
+}
+
+impl KernelLog {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            // the buffer never grows, logging must not allocate
+            if self.buffer.len() == LOG_CAPACITY {
+                self.buffer.pop_front();
+                self.dropped += 1;
+            }
+            self.buffer.push_back(byte);
+        }
+    }
+}
+
+impl Write for KernelLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+static LOG: Mutex<Option<KernelLog>> = Mutex::new(None);
+
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+// whether the next byte logged starts a line
+static LINE_START: AtomicBool = AtomicBool::new(true);
+
+// a timestamp formatted on the stack, logging must not allocate
+struct Stamp {
+    buffer: [u8; 32],
+    len: usize,
+}
+
+impl Write for Stamp {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Stamp {
+    fn now() -> Stamp {
+        let mut stamp = Stamp { buffer: [0; 32], len: 0 };
+        write!(stamp, "[{}] ", SystemTime::now()).ok();
+        stamp
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+// puts the stamp in front of every line
+struct Stamped<'a, W: Write> {
+    inner: &'a mut W,
+    stamp: Option<&'a str>,
+    line_start: bool,
+}
+
+impl<'a, W: Write> Stamped<'a, W> {
+    fn new(inner: &'a mut W, stamp: Option<&'a str>, line_start: bool) -> Self {
+        Stamped { inner, stamp, line_start }
+    }
+}
+
+impl<'a, W: Write> Write for Stamped<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let stamp = match self.stamp {
+            Some(stamp) => stamp,
+            None => return self.inner.write_str(s),
+        };
+        let mut rest = s;
+        while !rest.is_empty() {
+            if self.line_start {
+                self.inner.write_str(stamp)?;
+                self.line_start = false;
+            }
+            let end = match rest.find('\n') {
+                Some(newline) => {
+                    self.line_start = true;
+                    newline + 1
+                }
+                None => rest.len(),
+            };
+            self.inner.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+        Ok(())
+    }
+}
+
+/// prefix every line logged from now on with the wall clock time
+pub fn enable_timestamps() {
+    TIMESTAMPS.store(true, Ordering::Relaxed);
+}
+
+/// set up the kernel log once the heap exists, and replay the early log into it
+pub fn init() {
+    let mut log = KernelLog {
+        buffer: VecDeque::with_capacity(LOG_CAPACITY),
+        dropped: 0,
+    };
+    interrupts::without_interrupts(|| {
+        let early = EARLY_LOG.lock();
+        log.push(&early.buffer[..early.len]);
+        if early.dropped > 0 {
+            write!(log, "log: {} bytes of the early log were lost\n", early.dropped).ok();
+        }
+        *LOG.lock() = Some(log);
+    });
+}
+
+// returns false without writing anything when not `blocking` and a lock is busy
+fn write_log(args: fmt::Arguments, blocking: bool) -> bool {
+    let stamp = if TIMESTAMPS.load(Ordering::Relaxed) { Some(Stamp::now()) } else { None };
+    let stamp = stamp.as_ref().map(Stamp::as_str);
+    let line_start = LINE_START.load(Ordering::Relaxed);
+
+    let mut log = match lock(&LOG, blocking) {
+        Some(log) => log,
+        None => return false,
+    };
+    let line_start = match log.as_mut() {
+        Some(log) => {
+            let mut com1 = match lock(&*COM1, blocking) {
+                Some(com1) => com1,
+                None => return false,
+            };
+            Stamped::new(&mut *com1, stamp, line_start)
+                .write_fmt(args).expect("Writing failed");
+            let mut log = Stamped::new(log, stamp, line_start);
+            log.write_fmt(args).ok();
+            log.line_start
+        }
+        None => {
+            let (mut serial, mut early_log) = match (early_serial(blocking), lock(&EARLY_LOG, blocking)) {
+                (Some(serial), Some(early_log)) => (serial, early_log),
+                _ => return false,
+            };
+            Stamped::new(&mut *serial, stamp, line_start)
+                .write_fmt(args).expect("Writing failed");
+            let mut early = Stamped::new(&mut *early_log, stamp, line_start);
+            early.write_fmt(args).ok();
+            early.line_start
+        }
+    };
+    LINE_START.store(line_start, Ordering::Relaxed);
+    true
+}
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| write_log(args, true));
+}
+
+/// log without waiting for any lock, for handlers that may have interrupted
+/// the logger itself. When a lock is busy the text only goes to the UART,
+/// possibly in the middle of the line that was being written.
+pub fn log_nonblocking(args: fmt::Arguments) {
+    if !interrupts::without_interrupts(|| write_log(args, false)) {
+        let mut uart = unsafe { Serial::new(0x3f8) };
+        uart.write_fmt(args).ok();
+    }
+}
+
+/// the kernel log as it is buffered, oldest line first
+pub fn contents() -> String {
+    let bytes: Vec<u8> = interrupts::without_interrupts(|| {
+        LOG.lock().as_ref().map_or(Vec::new(), |log| log.buffer.iter().copied().collect())
+    });
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// log to the serial console and the kernel log, usable from the first
+/// line of `sos::init` on
+#[macro_export]
+macro_rules! klog {
+    ($($arg:tt)*) => ($crate::log::_log(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! klogln {
+    () => ($crate::klog!("\n"));
+    ($($arg:tt)*) => ($crate::klog!("{}\n", format_args!($($arg)*)));
+}
+
+#[test_case]
+fn test_early_log_replayed() {
+    crate::klogln!("log test {}", 42);
+    let contents = contents();
+    assert!(contents.starts_with("sos: "));
+    assert!(contents.contains("log test 42\n"));
+}
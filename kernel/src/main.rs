@@ -32,9 +32,11 @@ pub fn kernel_start(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    use sos::utils::hlt_loop;
+    use sos::{backtrace::Backtrace, gdb, utils::hlt_loop};
 
     println!("{}", info);
+    println!("{}", Backtrace::capture());
+    gdb::on_panic();
     hlt_loop();
 }
 
@@ -0,0 +1,601 @@
+use super::{kernel_root, phys_to_virt, physical_memory_offset, BootInfoFrameAllocator,
+            PAGE_ALLOCATOR, USER_SPACE_START, USER_SPACE_END};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::{
+        mapper::{MapToError, MapperAllSizes, UnmapError},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags as Flags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const PML4_ENTRY_SIZE: u64 = 1 << 39;
+const USER_PML4_START: usize = (USER_SPACE_START / PML4_ENTRY_SIZE) as usize;
+const USER_PML4_END: usize = (USER_SPACE_END / PML4_ENTRY_SIZE) as usize;
+
+// 0 is left to the kernel address space
+const MAX_PCID: u16 = 4095;
+// bit 63 of CR3 keeps the TLB entries of the new PCID
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+// bits left to the OS in every entry: a read only page that becomes
+// private on the first write, and a page deliberately shared between spaces
+const COPY_ON_WRITE: Flags = Flags::BIT_9;
+const SHARED: Flags = Flags::BIT_10;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+// ids start at 1, so a pcid nobody activated yet has no owner
+static NEXT_SPACE_ID: AtomicU64 = AtomicU64::new(1);
+// the space whose entries a pcid may still hold in the TLB
+static PCID_OWNER: [AtomicU64; MAX_PCID as usize + 1] = [AtomicU64::new(0); MAX_PCID as usize + 1];
+
+/// enable process-context identifiers when the CPU supports them
+pub fn init() {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    if cpuid.ecx & (1 << 17) == 0 {
+        return;
+    }
+    // PCIDE may only be set while the current PCID is 0, which it is at boot
+    unsafe {
+        Cr4::update(|f| f.insert(Cr4Flags::PCID));
+    }
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+
+    let cpuid = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+    INVPCID_ENABLED.store(cpuid.ebx & (1 << 10) != 0, Ordering::Relaxed);
+}
+
+fn next_pcid() -> Option<u16> {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    // pcids are recycled, activating a space flushes when another one
+    // used its pcid since
+    let pcid = NEXT_PCID.fetch_add(1, Ordering::Relaxed) % MAX_PCID + 1;
+    Some(pcid)
+}
+
+unsafe fn write_cr3(root: PhysFrame, pcid: u16, flush: bool) {
+    let mut value = root.start_address().as_u64() | pcid as u64;
+    if !flush && PCID_ENABLED.load(Ordering::Relaxed) {
+        value |= CR3_NO_FLUSH;
+    }
+    asm!("mov cr3, {}", in(reg) value, options(nostack));
+}
+
+// drop the translations of every pcid, global ones included
+fn flush_all_contexts() {
+    if INVPCID_ENABLED.load(Ordering::Relaxed) {
+        // type 2 ignores the descriptor, it only has to be readable
+        let descriptor = [0u64; 2];
+        unsafe {
+            asm!("invpcid {}, [{}]", in(reg) 2u64, in(reg) &descriptor, options(nostack));
+        }
+        return;
+    }
+    // clearing PCIDE flushes every pcid, which needs pcid 0 in CR3
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let cr3: u64;
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+        write_cr3(kernel_root(), 0, true);
+        Cr4::update(|f| f.remove(Cr4Flags::PCID));
+        Cr4::update(|f| f.insert(Cr4Flags::PCID));
+        asm!("mov cr3, {}", in(reg) cr3 & !CR3_NO_FLUSH, options(nostack));
+    });
+}
+
+/// forget kernel half mappings that were changed or removed. The kernel
+/// half is shared by all address spaces, but every pcid caches it apart.
+pub fn flush_kernel_mappings() {
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        flush_all_contexts();
+    } else {
+        tlb::flush_all();
+    }
+}
+
+fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr() }
+}
+
+// the last level entry of `page`, if all tables on the way are present
+fn leaf_entry(root: PhysFrame, page: Page) -> Option<&'static mut PageTableEntry> {
+    let indices = [page.p4_index(), page.p3_index(), page.p2_index()];
+    let mut frame = root;
+    for &index in indices.iter() {
+        let entry = &table(frame)[index];
+        if !entry.flags().contains(Flags::PRESENT) || entry.flags().contains(Flags::HUGE_PAGE) {
+            return None;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    let entry = &mut table(frame)[page.p1_index()];
+    if entry.flags().contains(Flags::PRESENT) {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+// call `f` with every present last level entry of the user half
+fn for_each_user_leaf(root: PhysFrame, f: &mut impl FnMut(Page, &mut PageTableEntry)) {
+    fn walk(frame: PhysFrame, level: u8, base: u64,
+            f: &mut impl FnMut(Page, &mut PageTableEntry)) {
+        let shift = 12 + 9 * (level as u64 - 1);
+        for (index, entry) in table(frame).iter_mut().enumerate() {
+            if !entry.flags().contains(Flags::PRESENT) {
+                continue;
+            }
+            let addr = base | (index as u64) << shift;
+            if level == 1 {
+                f(Page::containing_address(VirtAddr::new(addr)), entry);
+            } else {
+                assert!(!entry.flags().contains(Flags::HUGE_PAGE), "huge pages in user space");
+                walk(PhysFrame::containing_address(entry.addr()), level - 1, addr, f);
+            }
+        }
+    }
+
+    let pml4 = table(root);
+    for index in USER_PML4_START..USER_PML4_END {
+        if pml4[index].flags().contains(Flags::PRESENT) {
+            let next = PhysFrame::containing_address(pml4[index].addr());
+            walk(next, 3, (index as u64) << 39, f);
+        }
+    }
+}
+
+// give a copy-on-write entry a frame of its own, or take over the frame
+// when nobody else uses it any more. The caller flushes the TLB.
+fn resolve_cow(entry: &mut PageTableEntry, frame_allocator: &mut BootInfoFrameAllocator) -> bool {
+    let flags = (entry.flags() - COPY_ON_WRITE) | Flags::WRITABLE;
+    let frame = PhysFrame::containing_address(entry.addr());
+    if frame_allocator.ref_count(frame) == 1 {
+        entry.set_flags(flags);
+        return true;
+    }
+
+    let copy = match frame_allocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                                       phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                                       4096);
+        frame_allocator.release_frame(frame);
+    }
+    entry.set_addr(copy.start_address(), flags);
+    true
+}
+
+/// resolve a write fault at `addr` in the active address space,
+/// returns false if it was not caused by a copy-on-write page.
+pub fn handle_cow_fault(addr: VirtAddr) -> bool {
+    let page = Page::containing_address(addr);
+    let entry = match leaf_entry(Cr3::read().0, page) {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return false,
+    };
+    // nothing writes through a copy-on-write mapping while holding the
+    // allocator, copy_to resolves such pages before it writes. Should
+    // that change, fail the fault rather than spin on our own lock.
+    let mut frame_allocator = match PAGE_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    let resolved = resolve_cow(entry, frame_allocator.as_mut().unwrap());
+    drop(frame_allocator);
+    if resolved {
+        tlb::flush(page.start_address());
+    }
+    resolved
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    /// the page is outside of the user half
+    NotUserPage,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(e: MapToError<Size4KiB>) -> Self {
+        MapError::Map(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum ShareError {
+    NotUserPage,
+    NotMapped,
+    CopyOnWrite,
+    Map(MapToError<Size4KiB>),
+}
+
+/// a user address space: its own lower half, and the kernel mappings
+/// shared with every other address space.
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: PhysFrame,
+    id: u64,
+    pcid: u16,
+    flushed: AtomicBool,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let root = PAGE_ALLOCATOR.lock().as_mut().unwrap()
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let space = AddressSpace {
+            root,
+            id: NEXT_SPACE_ID.fetch_add(1, Ordering::Relaxed),
+            pcid: next_pcid().unwrap_or(0),
+            flushed: AtomicBool::new(false),
+        };
+
+        let pml4 = table(root);
+        pml4.zero();
+        space.sync_kernel_entries();
+        Ok(space)
+    }
+
+    pub fn root(&self) -> PhysFrame {
+        self.root
+    }
+
+    pub fn pcid(&self) -> Option<u16> {
+        if PCID_ENABLED.load(Ordering::Relaxed) {
+            Some(self.pcid)
+        } else {
+            None
+        }
+    }
+
+    // kernel PML4 entries may have been added since the last activation
+    fn sync_kernel_entries(&self) {
+        let kernel = table(kernel_root());
+        let pml4 = table(self.root);
+        for index in (0..512).filter(|i| !(USER_PML4_START..USER_PML4_END).contains(i)) {
+            pml4[index] = kernel[index].clone();
+        }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(table(self.root), physical_memory_offset()) }
+    }
+
+    fn is_user_page(page: Page) -> bool {
+        let addr = page.start_address().as_u64();
+        addr >= USER_SPACE_START && addr < USER_SPACE_END
+    }
+
+    /// map `page` to a fresh zeroed frame, `flags` always gains
+    /// PRESENT and USER_ACCESSIBLE.
+    pub fn map_user(&mut self, page: Page, flags: Flags) -> Result<PhysFrame, MapError> {
+        self.map_anonymous(page, flags - COPY_ON_WRITE - SHARED)
+    }
+
+    /// like `map_user`, but the page stays shared with the spaces it is
+    /// forked into or shared with instead of being copied on write.
+    pub fn map_shared(&mut self, page: Page, flags: Flags) -> Result<PhysFrame, MapError> {
+        self.map_anonymous(page, (flags - COPY_ON_WRITE) | SHARED)
+    }
+
+    fn map_anonymous(&mut self, page: Page, flags: Flags) -> Result<PhysFrame, MapError> {
+        if !Self::is_user_page(page) {
+            return Err(MapError::NotUserPage);
+        }
+        let mut frame_allocator = PAGE_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096);
+        }
+
+        let flags = flags | Flags::PRESENT | Flags::USER_ACCESSIBLE;
+        let table_flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        let result = unsafe {
+            self.mapper().map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)
+        };
+        match result {
+            // the space may not be active, flushing here would be pointless
+            Ok(flush) => flush.ignore(),
+            Err(e) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(MapError::Map(e));
+            }
+        }
+        self.flush_page(page);
+        Ok(frame)
+    }
+
+    /// map the frame behind `page` at `dest` in `other` as well, both
+    /// mappings are marked shared. Copy-on-write pages can not be shared.
+    pub fn share(&mut self, page: Page, other: &mut AddressSpace, dest: Page)
+        -> Result<(), ShareError>
+    {
+        if !Self::is_user_page(dest) {
+            return Err(ShareError::NotUserPage);
+        }
+        let entry = leaf_entry(self.root, page).ok_or(ShareError::NotMapped)?;
+        if entry.flags().contains(COPY_ON_WRITE) {
+            return Err(ShareError::CopyOnWrite);
+        }
+        let frame = PhysFrame::containing_address(entry.addr());
+        let flags = entry.flags() | SHARED;
+
+        let mut frame_allocator = PAGE_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let table_flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        unsafe {
+            other.mapper().map_to_with_table_flags(dest, frame, flags, table_flags, frame_allocator)
+                .map_err(ShareError::Map)?
+                .ignore();
+        }
+        frame_allocator.share_frame(frame);
+        entry.set_flags(flags);
+        self.flush_page(page);
+        other.flush_page(dest);
+        Ok(())
+    }
+
+    /// a copy of this space where private pages are copied on write
+    /// and shared pages stay shared
+    pub fn fork(&mut self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut child = AddressSpace::new()?;
+        let result = self.fork_into(&mut child);
+        // pages of the parent may have lost their write permission
+        if self.is_active() {
+            // tlb::flush_all would reload CR3 without the pcid
+            unsafe { write_cr3(self.root, self.pcid, true) };
+        } else {
+            self.flushed.store(false, Ordering::Relaxed);
+        }
+        // on failure the child is dropped, releasing what it got so far
+        result.map(|_| child)
+    }
+
+    fn fork_into(&mut self, child: &mut AddressSpace) -> Result<(), MapToError<Size4KiB>> {
+        let mut frame_allocator = PAGE_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let table_flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+
+        let mut result = Ok(());
+        for_each_user_leaf(self.root, &mut |page, entry| {
+            if result.is_err() {
+                return;
+            }
+            let mut flags = entry.flags();
+            if !flags.contains(SHARED) && flags.intersects(Flags::WRITABLE | COPY_ON_WRITE) {
+                flags = (flags - Flags::WRITABLE) | COPY_ON_WRITE;
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            let mapped = unsafe {
+                child.mapper().map_to_with_table_flags(page, frame, flags, table_flags,
+                                                       frame_allocator)
+            };
+            match mapped {
+                Ok(flush) => {
+                    flush.ignore();
+                    frame_allocator.share_frame(frame);
+                    entry.set_flags(flags);
+                }
+                Err(e) => result = Err(e),
+            }
+        });
+        result
+    }
+
+    /// unmap `page` and drop its reference to the frame
+    pub fn unmap_user(&mut self, page: Page) -> Result<(), UnmapError> {
+        if !Self::is_user_page(page) {
+            return Err(UnmapError::PageNotMapped);
+        }
+        let (frame, flush) = self.mapper().unmap(page)?;
+        flush.ignore();
+        self.flush_page(page);
+        unsafe {
+            PAGE_ALLOCATOR.lock().as_mut().unwrap().release_frame(frame);
+        }
+        Ok(())
+    }
+
+    // a mapping of `page` was removed or lost a permission. When the space
+    // is not active its pcid may still cache the old one, so the next
+    // activation has to flush.
+    fn flush_page(&self, page: Page) {
+        if self.is_active() {
+            tlb::flush(page.start_address());
+        } else {
+            self.flushed.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// copy `data` to `addr` through the physical memory window,
+    /// so it works whether or not this space is active.
+    pub fn copy_to(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), ()> {
+        let mut copied = 0;
+        while copied < data.len() {
+            let addr = addr + copied as u64;
+            let page = Page::containing_address(addr);
+            let entry = leaf_entry(self.root, page).ok_or(())?;
+            // the physical window bypasses the fault that would copy the page
+            if entry.flags().contains(COPY_ON_WRITE) {
+                if !resolve_cow(entry, PAGE_ALLOCATOR.lock().as_mut().unwrap()) {
+                    return Err(());
+                }
+                self.flush_page(page);
+            }
+            let phys = self.translate(addr).ok_or(())?;
+            let len = (4096 - (addr.as_u64() % 4096) as usize).min(data.len() - copied);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[copied..].as_ptr(),
+                                               phys_to_virt(phys).as_mut_ptr(), len);
+            }
+            copied += len;
+        }
+        Ok(())
+    }
+
+    pub fn copy_from(&mut self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), ()> {
+        let mut copied = 0;
+        while copied < buf.len() {
+            let addr = addr + copied as u64;
+            let phys = self.translate(addr).ok_or(())?;
+            let len = (4096 - (addr.as_u64() % 4096) as usize).min(buf.len() - copied);
+            unsafe {
+                core::ptr::copy_nonoverlapping(phys_to_virt(phys).as_ptr(),
+                                               buf[copied..].as_mut_ptr(), len);
+            }
+            copied += len;
+        }
+        Ok(())
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.root
+    }
+
+    /// switch to this address space
+    pub unsafe fn activate(&self) {
+        self.sync_kernel_entries();
+        // a recycled pcid may still have entries of another space, or
+        // mappings may have changed while the space was not active
+        let owner = PCID_OWNER[self.pcid as usize].swap(self.id, Ordering::Relaxed);
+        let flushed = self.flushed.swap(true, Ordering::Relaxed);
+        let flush = owner != self.id || !flushed;
+        write_cr3(self.root, self.pcid, flush);
+    }
+}
+
+/// switch back to the address space the kernel booted with
+pub unsafe fn activate_kernel() {
+    write_cr3(kernel_root(), 0, false);
+}
+
+// free everything below `frame`: tables, and the user frames at the last level
+fn free_table(frame: PhysFrame, level: u8, frame_allocator: &mut BootInfoFrameAllocator) {
+    for entry in table(frame).iter() {
+        if !entry.flags().contains(Flags::PRESENT) {
+            continue;
+        }
+        let next = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            assert!(!entry.flags().contains(Flags::HUGE_PAGE), "huge pages in user space");
+            free_table(next, level - 1, frame_allocator);
+        } else {
+            unsafe { frame_allocator.release_frame(next) };
+        }
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { activate_kernel() };
+        }
+
+        let mut frame_allocator = PAGE_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let pml4 = table(self.root);
+        for index in USER_PML4_START..USER_PML4_END {
+            if pml4[index].flags().contains(Flags::PRESENT) {
+                let next = PhysFrame::containing_address(pml4[index].addr());
+                free_table(next, 3, frame_allocator);
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.root) };
+    }
+}
+
+#[test_case]
+fn test_address_space_isolation() {
+    let page = Page::containing_address(VirtAddr::new(USER_SPACE_START + 0x1000));
+    let mut first = AddressSpace::new().expect("failed to create address space");
+    let mut second = AddressSpace::new().expect("failed to create address space");
+    first.map_user(page, Flags::WRITABLE).expect("map_user failed");
+    first.copy_to(page.start_address(), b"first").unwrap();
+    assert!(second.translate(page.start_address()).is_none());
+
+    unsafe {
+        first.activate();
+        let data = core::slice::from_raw_parts(page.start_address().as_ptr::<u8>(), 5);
+        // SMAP forbids the kernel from reading user pages directly
+        let readable = !Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+        if readable {
+            assert_eq!(data, b"first");
+        }
+        activate_kernel();
+    }
+
+    second.map_user(page, Flags::WRITABLE).expect("map_user failed");
+    first.unmap_user(page).expect("unmap_user failed");
+    assert!(first.translate(page.start_address()).is_none());
+    assert!(second.translate(page.start_address()).is_some());
+}
+
+#[test_case]
+fn test_fork_copy_on_write() {
+    let private = Page::containing_address(VirtAddr::new(USER_SPACE_START + 0x1000));
+    let shared = Page::containing_address(VirtAddr::new(USER_SPACE_START + 0x2000));
+    let mut parent = AddressSpace::new().expect("failed to create address space");
+    parent.map_user(private, Flags::WRITABLE).expect("map_user failed");
+    parent.map_shared(shared, Flags::WRITABLE).expect("map_shared failed");
+    parent.copy_to(private.start_address(), b"parent").unwrap();
+
+    let mut child = parent.fork().expect("fork failed");
+    assert_eq!(parent.translate(private.start_address()), child.translate(private.start_address()));
+
+    child.copy_to(private.start_address(), b"child!").unwrap();
+    child.copy_to(shared.start_address(), b"shared").unwrap();
+    assert_ne!(parent.translate(private.start_address()), child.translate(private.start_address()));
+
+    let mut buf = [0; 6];
+    parent.copy_from(private.start_address(), &mut buf).unwrap();
+    assert_eq!(&buf, b"parent");
+    parent.copy_from(shared.start_address(), &mut buf).unwrap();
+    assert_eq!(&buf, b"shared");
+}
+
+#[test_case]
+fn test_copy_on_write_fault() {
+    let page = Page::containing_address(VirtAddr::new(USER_SPACE_START + 0x1000));
+    let mut parent = AddressSpace::new().expect("failed to create address space");
+    parent.map_user(page, Flags::WRITABLE).expect("map_user failed");
+    parent.copy_to(page.start_address(), b"parent").unwrap();
+    let mut child = parent.fork().expect("fork failed");
+
+    // the write faults on the read only page and handle_cow_fault copies it
+    unsafe {
+        parent.activate();
+        let smap = Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+        if smap {
+            asm!("stac", options(nomem, nostack));
+        }
+        core::ptr::write_volatile(page.start_address().as_mut_ptr::<u8>(), b'P');
+        if smap {
+            asm!("clac", options(nomem, nostack));
+        }
+        activate_kernel();
+    }
+
+    assert_ne!(parent.translate(page.start_address()), child.translate(page.start_address()));
+    let mut buf = [0; 6];
+    parent.copy_from(page.start_address(), &mut buf).unwrap();
+    assert_eq!(&buf, b"Parent");
+    child.copy_from(page.start_address(), &mut buf).unwrap();
+    assert_eq!(&buf, b"parent");
+}
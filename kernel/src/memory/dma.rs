@@ -0,0 +1,280 @@
+use super::{phys_to_virt, DMA_ZONE_SIZE};
+use alloc::vec::Vec;
+use core::slice;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+const ZONE_FRAMES: usize = (DMA_ZONE_SIZE / FRAME_SIZE) as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    ZeroSize,
+    InvalidConstraints,
+    NotInitialized,
+    OutOfMemory,
+}
+
+/// where a DMA buffer may be placed in physical memory
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+    /// power of two, at least a page
+    pub align: u64,
+    /// power of two the buffer must not cross, if any
+    pub boundary: Option<u64>,
+    /// the buffer must end at or below this address
+    pub limit: u64,
+}
+
+impl DmaConstraints {
+    pub const fn new() -> Self {
+        DmaConstraints {
+            align: FRAME_SIZE,
+            boundary: None,
+            limit: 0x1_0000_0000,
+        }
+    }
+
+    pub const fn align(self, align: u64) -> Self {
+        DmaConstraints { align, ..self }
+    }
+
+    pub const fn boundary(self, boundary: u64) -> Self {
+        DmaConstraints { boundary: Some(boundary), ..self }
+    }
+
+    pub const fn limit(self, limit: u64) -> Self {
+        DmaConstraints { limit, ..self }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.align.is_power_of_two() && self.align >= FRAME_SIZE
+            && self.boundary.map_or(true, |b| b.is_power_of_two() && b >= FRAME_SIZE)
+    }
+
+    fn allows(&self, start: u64, size: u64) -> bool {
+        let end = start + size;
+        start % self.align == 0
+            && end <= self.limit
+            && self.boundary.map_or(true, |b| start / b == (end - 1) / b)
+    }
+}
+
+// a bitmap over the frames set aside by the frame allocator
+struct DmaZone {
+    start: PhysFrame,
+    frames: usize,
+    used: [u64; ZONE_FRAMES / 64],
+}
+
+impl DmaZone {
+    fn is_used(&self, index: usize) -> bool {
+        self.used[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        if used {
+            self.used[index / 64] |= 1 << (index % 64);
+        } else {
+            self.used[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    fn alloc(&mut self, count: usize, constraints: &DmaConstraints) -> Option<PhysFrame> {
+        let base = self.start.start_address().as_u64();
+        let size = count as u64 * FRAME_SIZE;
+        let mut index = 0;
+        while index + count <= self.frames {
+            let start = base + index as u64 * FRAME_SIZE;
+            if !constraints.allows(start, size) {
+                index += 1;
+                continue;
+            }
+            match (index..index + count).find(|&i| self.is_used(i)) {
+                Some(used) => index = used + 1,
+                None => {
+                    for i in index..index + count {
+                        self.set_used(i, true);
+                    }
+                    return Some(self.start + index as u64);
+                }
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, start: PhysFrame, count: usize) {
+        let index = (start - self.start) as usize;
+        for i in index..index + count {
+            assert!(self.is_used(i), "DMA frame freed twice");
+            self.set_used(i, false);
+        }
+    }
+}
+
+static DMA_ZONE: Mutex<Option<DmaZone>> = Mutex::new(None);
+
+pub fn init(zone: Option<PhysFrameRange>) {
+    if let Some(zone) = zone {
+        *DMA_ZONE.lock() = Some(DmaZone {
+            start: zone.start,
+            frames: ((zone.end - zone.start) as usize).min(ZONE_FRAMES),
+            used: [0; ZONE_FRAMES / 64],
+        });
+    }
+}
+
+/// a physically contiguous buffer for device DMA, freed on drop
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    len: usize,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        phys_to_virt(self.phys)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Some(zone) = DMA_ZONE.lock().as_mut() {
+            zone.free(PhysFrame::containing_address(self.phys), self.frames);
+        }
+    }
+}
+
+/// allocate a zeroed buffer of `size` bytes that satisfies `constraints`
+pub fn alloc_dma(size: usize, constraints: DmaConstraints) -> Result<DmaBuffer, DmaError> {
+    if size == 0 {
+        return Err(DmaError::ZeroSize);
+    }
+    if !constraints.is_valid() {
+        return Err(DmaError::InvalidConstraints);
+    }
+    if let Some(boundary) = constraints.boundary {
+        if size as u64 > boundary {
+            return Err(DmaError::InvalidConstraints);
+        }
+    }
+
+    let frames = (size as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+    let start = DMA_ZONE.lock().as_mut()
+        .ok_or(DmaError::NotInitialized)?
+        .alloc(frames as usize, &constraints)
+        .ok_or(DmaError::OutOfMemory)?;
+
+    let mut buffer = DmaBuffer {
+        phys: start.start_address(),
+        len: size,
+        frames: frames as usize,
+    };
+    for byte in buffer.as_mut_slice() {
+        *byte = 0;
+    }
+    Ok(buffer)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgEntry {
+    pub phys: PhysAddr,
+    pub len: usize,
+}
+
+/// a buffer made of several contiguous segments, as consumed by
+/// descriptor based devices. Physically adjacent segments are merged.
+#[derive(Debug)]
+pub struct ScatterGatherList {
+    entries: Vec<SgEntry>,
+    buffers: Vec<DmaBuffer>,
+}
+
+impl ScatterGatherList {
+    /// allocate `size` bytes in segments of at most `max_segment` bytes
+    pub fn alloc(size: usize, max_segment: usize, constraints: DmaConstraints)
+        -> Result<Self, DmaError>
+    {
+        if size == 0 || max_segment == 0 {
+            return Err(DmaError::ZeroSize);
+        }
+
+        let mut list = ScatterGatherList {
+            entries: Vec::new(),
+            buffers: Vec::new(),
+        };
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = remaining.min(max_segment);
+            let buffer = alloc_dma(len, constraints)?;
+            list.push(buffer.phys_addr(), len, max_segment);
+            list.buffers.push(buffer);
+            remaining -= len;
+        }
+        Ok(list)
+    }
+
+    fn push(&mut self, phys: PhysAddr, len: usize, max_segment: usize) {
+        if let Some(last) = self.entries.last_mut() {
+            if last.phys + last.len as u64 == phys && last.len + len <= max_segment {
+                last.len += len;
+                return;
+            }
+        }
+        self.entries.push(SgEntry { phys, len });
+    }
+
+    pub fn entries(&self) -> &[SgEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().map(|e| e.len).sum()
+    }
+
+    pub fn buffers_mut(&mut self) -> &mut [DmaBuffer] {
+        &mut self.buffers
+    }
+}
+
+#[test_case]
+fn test_dma_constraints() {
+    let constraints = DmaConstraints::new().align(0x4000).boundary(0x10000).limit(0x1_0000_0000);
+    let buffer = alloc_dma(0x3000, constraints).expect("alloc_dma failed");
+    let start = buffer.phys_addr().as_u64();
+    assert_eq!(start % 0x4000, 0);
+    assert_eq!(start / 0x10000, (start + 0x3000 - 1) / 0x10000);
+    assert!(buffer.as_slice().iter().all(|&b| b == 0));
+
+    drop(buffer);
+    let again = alloc_dma(0x3000, constraints).expect("alloc_dma failed");
+    assert_eq!(again.phys_addr().as_u64(), start);
+}
+
+#[test_case]
+fn test_scatter_gather_list() {
+    let list = ScatterGatherList::alloc(5 * 4096, 2 * 4096, DmaConstraints::new())
+        .expect("alloc failed");
+    assert_eq!(list.len(), 5 * 4096);
+    assert!(list.entries().iter().all(|e| e.len <= 2 * 4096));
+}
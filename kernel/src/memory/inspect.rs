@@ -0,0 +1,217 @@
+use super::{phys_to_virt, USER_SPACE_START, USER_SPACE_END};
+use crate::serial_println;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{page_table::PageTableEntry, PageTable, PageTableFlags as Flags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// a run of virtually and physically contiguous pages with the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub len: u64,
+    pub page_size: u64,
+    /// flags of the last level, with WRITABLE, USER_ACCESSIBLE and
+    /// NO_EXECUTE as they take effect through all levels
+    pub flags: Flags,
+}
+
+impl Mapping {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr.as_u64() - self.start.as_u64() < self.len
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags.contains(Flags::WRITABLE)
+    }
+
+    pub fn executable(&self) -> bool {
+        !self.flags.contains(Flags::NO_EXECUTE)
+    }
+
+    pub fn user_accessible(&self) -> bool {
+        self.flags.contains(Flags::USER_ACCESSIBLE)
+    }
+
+    pub fn in_user_space(&self) -> bool {
+        self.start.as_u64() >= USER_SPACE_START && self.end().as_u64() <= USER_SPACE_END
+    }
+
+    fn extends(&self, next: &Mapping) -> bool {
+        self.end() == next.start
+            && self.phys + self.len == next.phys
+            && self.page_size == next.page_size
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = match self.page_size {
+            0x1000 => "4K",
+            0x20_0000 => "2M",
+            _ => "1G",
+        };
+        write!(f, "{:#018x}-{:#018x} -> {:#014x}-{:#014x} {} {}{}{} {:?}",
+               self.start.as_u64(), self.end().as_u64(),
+               self.phys.as_u64(), self.phys.as_u64() + self.len, size,
+               if self.writable() { 'w' } else { '-' },
+               if self.executable() { 'x' } else { '-' },
+               if self.user_accessible() { 'u' } else { '-' },
+               self.flags - Flags::WRITABLE - Flags::NO_EXECUTE - Flags::USER_ACCESSIBLE)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Violation {
+    WritableExecutable(Mapping),
+    UserAccessibleKernelPage(Mapping),
+}
+
+fn table(frame: PhysFrame) -> &'static PageTable {
+    unsafe { &*phys_to_virt(frame.start_address()).as_ptr() }
+}
+
+fn canonical(addr: u64) -> VirtAddr {
+    if addr & (1 << 47) != 0 {
+        VirtAddr::new(addr | 0xffff_0000_0000_0000)
+    } else {
+        VirtAddr::new(addr)
+    }
+}
+
+// the frame a leaf maps. Bit 12 of a 2M or 1G entry is its PAT bit, not
+// part of the address.
+fn leaf_addr(entry: &PageTableEntry, page_size: u64) -> PhysAddr {
+    PhysAddr::new(entry.addr().as_u64() & !(page_size - 1))
+}
+
+// inherited is what the upper levels allow, leaves get their final flags here
+fn walk(frame: PhysFrame, level: u8, base: u64, inherited: Flags, f: &mut impl FnMut(Mapping)) {
+    let shift = 12 + 9 * (level as u64 - 1);
+    for (index, entry) in table(frame).iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(Flags::PRESENT) {
+            continue;
+        }
+
+        let addr = base | (index as u64) << shift;
+        let effective = (inherited & flags & (Flags::WRITABLE | Flags::USER_ACCESSIBLE))
+            | ((inherited | flags) & Flags::NO_EXECUTE);
+        if level == 1 || flags.contains(Flags::HUGE_PAGE) {
+            // accessed and dirty change all the time and would split equal runs
+            let other = flags - Flags::ACCESSED - Flags::DIRTY
+                - Flags::WRITABLE - Flags::USER_ACCESSIBLE - Flags::NO_EXECUTE;
+            f(Mapping {
+                start: canonical(addr),
+                phys: leaf_addr(entry, 1 << shift),
+                len: 1 << shift,
+                page_size: 1 << shift,
+                flags: other | effective,
+            });
+        } else {
+            let next = PhysFrame::containing_address(entry.addr());
+            walk(next, level - 1, addr, effective, f);
+        }
+    }
+}
+
+/// call `f` for every merged run mapped by the PML4 in `root`, in address order
+pub fn for_each_mapping(root: PhysFrame, mut f: impl FnMut(&Mapping)) {
+    let mut current: Option<Mapping> = None;
+    walk(root, 4, 0, Flags::WRITABLE | Flags::USER_ACCESSIBLE, &mut |mapping| {
+        if let Some(run) = current.as_mut() {
+            if run.extends(&mapping) {
+                run.len += mapping.len;
+                return;
+            }
+        }
+        if let Some(run) = current.replace(mapping) {
+            f(&run);
+        }
+    });
+    if let Some(run) = current {
+        f(&run);
+    }
+}
+
+pub fn mappings(root: PhysFrame) -> Vec<Mapping> {
+    let mut mappings = Vec::new();
+    for_each_mapping(root, |mapping| mappings.push(*mapping));
+    mappings
+}
+
+/// the run mapping `addr`, if any
+pub fn query(root: PhysFrame, addr: VirtAddr) -> Option<Mapping> {
+    let mut found = None;
+    for_each_mapping(root, |mapping| {
+        if mapping.contains(addr) {
+            found = Some(*mapping);
+        }
+    });
+    found
+}
+
+/// W+X mappings and kernel pages reachable from user mode
+pub fn audit(root: PhysFrame) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for_each_mapping(root, |mapping| {
+        if mapping.writable() && mapping.executable() {
+            violations.push(Violation::WritableExecutable(*mapping));
+        }
+        if mapping.user_accessible() && !mapping.in_user_space() {
+            violations.push(Violation::UserAccessibleKernelPage(*mapping));
+        }
+    });
+    violations
+}
+
+pub fn dump(root: PhysFrame) {
+    serial_println!("page table at {:#x}:", root.start_address().as_u64());
+    for_each_mapping(root, |mapping| serial_println!("  {}", mapping));
+}
+
+pub fn active_root() -> PhysFrame {
+    Cr3::read().0
+}
+
+/// where `addr` is mapped to by the active page table. Takes no lock, for
+/// code that may run while the mapper is held.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut frame = active_root();
+    for (level, &index) in indices.iter().enumerate() {
+        let entry = &table(frame)[index];
+        if !entry.flags().contains(Flags::PRESENT) {
+            return None;
+        }
+        // 1G, 2M and 4K pages, the PML4 maps none itself
+        let page_size = 1u64 << (39 - 9 * level);
+        if level == 3 || (level != 0 && entry.flags().contains(Flags::HUGE_PAGE)) {
+            return Some(leaf_addr(entry, page_size) + (addr.as_u64() & (page_size - 1)));
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    None
+}
+
+#[test_case]
+fn test_query_heap() {
+    use crate::allocator::HEAP_START;
+
+    let mapping = query(active_root(), VirtAddr::new(HEAP_START as u64))
+        .expect("heap is not mapped");
+    assert!(mapping.writable());
+    assert!(!mapping.user_accessible());
+    assert_eq!(mapping.page_size, 4096);
+    assert!(query(active_root(), VirtAddr::new(0xdead_beef_0000)).is_none());
+    assert_eq!(translate(VirtAddr::new(HEAP_START as u64 + 8)), Some(mapping.phys + 8u64));
+}
@@ -0,0 +1,163 @@
+use super::{phys_to_virt, BootInfoFrameAllocator, PHYSICAL_MEMORY_OFFSET};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+const MAX_RESERVED: usize = 32;
+// leave the first megabyte to firmware and the boot code
+const MIN_ADDR: u64 = 0x10_0000;
+
+/// physical ranges taken before the frame allocator existed
+#[derive(Debug, Clone, Copy)]
+pub struct Reserved {
+    ranges: [(u64, u64); MAX_RESERVED],
+    count: usize,
+}
+
+impl Reserved {
+    const fn new() -> Self {
+        Reserved {
+            ranges: [(0, 0); MAX_RESERVED],
+            count: 0,
+        }
+    }
+
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges[..self.count]
+    }
+
+    /// whether any byte of `frame` is reserved, ranges need not be frame aligned
+    pub fn contains(&self, frame: PhysFrame) -> bool {
+        let frame_start = frame.start_address().as_u64();
+        let frame_end = frame_start + frame.size();
+        self.overlapping(frame_start, frame_end).is_some()
+    }
+
+    fn overlapping(&self, start: u64, end: u64) -> Option<(u64, u64)> {
+        self.ranges().iter().copied().find(|&(s, e)| s < end && start < e)
+    }
+
+    fn insert(&mut self, start: u64, end: u64) -> bool {
+        // grow a touching range instead of taking a new slot
+        for range in self.ranges[..self.count].iter_mut() {
+            if range.0 == end {
+                range.0 = start;
+                return true;
+            }
+            if range.1 == start {
+                range.1 = end;
+                return true;
+            }
+        }
+        if self.count == MAX_RESERVED {
+            return false;
+        }
+        self.ranges[self.count] = (start, end);
+        self.count += 1;
+        true
+    }
+}
+
+struct MemBlock {
+    memory_map: Option<&'static MemoryMap>,
+    reserved: Reserved,
+    frozen: bool,
+}
+
+static MEMBLOCK: Mutex<MemBlock> = Mutex::new(MemBlock {
+    memory_map: None,
+    reserved: Reserved::new(),
+    frozen: false,
+});
+
+/// make the boot memory map available for early allocations, the very
+/// first thing during boot
+pub fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let mut memblock = MEMBLOCK.lock();
+    memblock.memory_map = Some(memory_map);
+    // the frame allocator keeps the DMA zone for itself, never hand it out here
+    if let Some(zone) = BootInfoFrameAllocator::find_dma_zone(memory_map) {
+        memblock.reserved.insert(zone.start.start_address().as_u64(),
+                                 zone.end.start_address().as_u64());
+    }
+}
+
+/// allocate `size` zeroed bytes of physical memory before the frame
+/// allocator exists. The memory is never freed.
+pub fn alloc(size: u64, align: u64) -> Option<PhysAddr> {
+    assert!(align.is_power_of_two(), "memblock alignment must be a power of two");
+    let mut memblock = MEMBLOCK.lock();
+    if memblock.frozen || size == 0 {
+        return None;
+    }
+    let memory_map = memblock.memory_map?;
+
+    // top down, so that low memory stays free for devices
+    let regions = memory_map.iter().rev()
+        .filter(|r| r.region_type == MemoryRegionType::Usable);
+    for region in regions {
+        let bottom = region.range.start_addr().max(MIN_ADDR);
+        let mut end = region.range.end_addr();
+        while end >= bottom + size {
+            let start = (end - size) & !(align - 1);
+            if start < bottom {
+                break;
+            }
+            match memblock.reserved.overlapping(start, start + size) {
+                Some((taken, _)) => end = taken,
+                None => {
+                    if !memblock.reserved.insert(start, start + size) {
+                        return None;
+                    }
+                    let addr = PhysAddr::new(start);
+                    unsafe {
+                        core::ptr::write_bytes(phys_to_virt(addr).as_mut_ptr::<u8>(), 0, size as usize);
+                    }
+                    return Some(addr);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// stop early allocations and hand what they took to the frame allocator
+pub(super) fn freeze() -> Reserved {
+    let mut memblock = MEMBLOCK.lock();
+    memblock.frozen = true;
+    memblock.reserved
+}
+
+/// usable memory in the boot memory map, in bytes
+pub fn usable_memory() -> u64 {
+    MEMBLOCK.lock().memory_map.map_or(0, |memory_map| {
+        memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.end_addr() - r.range.start_addr())
+            .sum()
+    })
+}
+
+#[test_case]
+fn test_memblock_frozen() {
+    use super::PAGE_ALLOCATOR;
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    // the frame allocator took over during boot
+    assert!(alloc(4096, 4096).is_none());
+    let mut frame_allocator = PAGE_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let frame = frame_allocator.allocate_frame().unwrap();
+    assert!(!MEMBLOCK.lock().reserved.contains(frame));
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn test_reserved_partial_frame() {
+    let mut reserved = Reserved::new();
+    reserved.insert(0x20_0800, 0x20_0900);
+    assert!(reserved.contains(PhysFrame::containing_address(PhysAddr::new(0x20_0000))));
+    assert!(!reserved.contains(PhysFrame::containing_address(PhysAddr::new(0x20_1000))));
+}
@@ -0,0 +1,243 @@
+use super::{PAGE_ALLOCATOR, MAPPER};
+use alloc::collections::BTreeMap;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+pub const MMIO_START: usize = 0x_6666_0000_0000;
+pub const MMIO_SIZE: usize = 1024 * 1024 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+const IA32_PAT: u32 = 0x277;
+
+// memory types as encoded in the PAT MSR
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+// the power-on layout, except PA2 which becomes write-combining. Every mode
+// is then reachable with PWT and PCD alone, the PAT bit of a 4KiB entry is
+// bit 7, which the mapper takes for HUGE_PAGE and refuses on the last level.
+const PAT_LAYOUT: [u64; 8] = [PAT_WB, PAT_WT, PAT_WC, PAT_UC,
+                              PAT_WB, PAT_WT, PAT_UC_MINUS, PAT_UC];
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    Uncacheable,
+    WriteCombining,
+}
+
+impl CacheMode {
+    // index into the PAT is PAT:PCD:PWT, the PAT bit is always left clear
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncacheable => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) =>
+                PageTableFlags::NO_CACHE,
+            // without a PAT, PCD alone would be UC- and there is no way to ask for WC
+            CacheMode::WriteCombining => CacheMode::Uncacheable.flags(),
+        }
+    }
+}
+
+/// program the PAT so that every `CacheMode` can be selected from a page table entry
+pub fn init_pat() {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    if cpuid.edx & (1 << 16) == 0 {
+        return;
+    }
+
+    let value = PAT_LAYOUT.iter().enumerate()
+        .fold(0, |value, (i, &entry)| value | entry << (i * 8));
+    unsafe {
+        Msr::new(IA32_PAT).write(value);
+        // lines cached under the old memory types must go
+        asm!("wbinvd", options(nostack));
+    }
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    ZeroLength,
+    OutOfVirtualSpace,
+    NotInitialized,
+    Map(MapToError<Size4KiB>),
+}
+
+lazy_static! {
+    // free virtual ranges of the MMIO window, start to size
+    static ref MMIO_SPACE: Mutex<BTreeMap<usize, usize>> = {
+        let mut space = BTreeMap::new();
+        space.insert(MMIO_START, MMIO_SIZE);
+        Mutex::new(space)
+    };
+}
+
+fn reserve(size: usize) -> Option<usize> {
+    let mut space = MMIO_SPACE.lock();
+    let (&start, &free) = space.iter().find(|(_, free)| **free >= size)?;
+    space.remove(&start);
+    if free > size {
+        space.insert(start + size, free - size);
+    }
+    Some(start)
+}
+
+fn release(start: usize, size: usize) {
+    let mut space = MMIO_SPACE.lock();
+    let mut start = start;
+    let mut size = size;
+
+    if let Some((&prev, &prev_size)) = space.range(..start).next_back() {
+        if prev + prev_size == start {
+            space.remove(&prev);
+            start = prev;
+            size += prev_size;
+        }
+    }
+    if let Some(&next_size) = space.get(&(start + size)) {
+        space.remove(&(start + size));
+        size += next_size;
+    }
+    space.insert(start, size);
+}
+
+/// device memory mapped into the MMIO window, unmapped on drop
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+    pages: usize,
+    mode: CacheMode,
+}
+
+impl MmioRegion {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base + (self.phys.as_u64() & (PAGE_SIZE as u64 - 1))
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn cache_mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt_addr().as_mut_ptr()
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= self.len, "MMIO read out of bounds");
+        unsafe { ptr::read_volatile((self.virt_addr().as_u64() as usize + offset) as *const T) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + core::mem::size_of::<T>() <= self.len, "MMIO write out of bounds");
+        unsafe { ptr::write_volatile((self.virt_addr().as_u64() as usize + offset) as *mut T, value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        if let Some(mapper) = MAPPER.lock().as_mut() {
+            let first = Page::<Size4KiB>::containing_address(self.base);
+            for page in Page::range(first, first + self.pages as u64) {
+                // the frames belong to the device, never free them.
+                // pages may be missing if mapping them failed.
+                if let Ok((_, flush)) = mapper.unmap(page) {
+                    flush.ignore();
+                }
+            }
+            super::address_space::flush_kernel_mappings();
+        }
+        release(self.base.as_u64() as usize, self.pages * PAGE_SIZE);
+    }
+}
+
+/// map `len` bytes of device memory at `phys` with the given cache mode.
+///
+/// the physical memory window still maps the same frames write-back,
+/// so device memory must only ever be touched through the returned region.
+pub fn map_mmio(phys: PhysAddr, len: usize, mode: CacheMode) -> Result<MmioRegion, MmioError> {
+    if len == 0 {
+        return Err(MmioError::ZeroLength);
+    }
+
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (len - 1) as u64);
+    let pages = (last_frame - first_frame) as usize + 1;
+
+    let base = reserve(pages * PAGE_SIZE).ok_or(MmioError::OutOfVirtualSpace)?;
+    let region = MmioRegion {
+        base: VirtAddr::new(base as u64),
+        phys,
+        len,
+        pages,
+        mode,
+    };
+
+    // on failure region is dropped here, which unmaps what was mapped so far
+    map_frames(region.base, first_frame, last_frame, mode)?;
+    Ok(region)
+}
+
+fn map_frames(base: VirtAddr, first_frame: PhysFrame, last_frame: PhysFrame, mode: CacheMode)
+    -> Result<(), MmioError>
+{
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = PAGE_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MmioError::NotInitialized),
+    };
+
+    let flags = super::data_page_flags() | mode.flags();
+    let first_page = Page::containing_address(base);
+    for frame in PhysFrame::range_inclusive(first_frame, last_frame) {
+        let page = first_page + (frame - first_frame);
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)
+                .map_err(MmioError::Map)?
+                .flush();
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_map_mmio_vga_buffer() {
+    let region = map_mmio(PhysAddr::new(0xb8000), 80 * 25 * 2, CacheMode::WriteCombining)
+        .expect("map_mmio failed");
+    let base = region.virt_addr();
+    region.write::<u16>(0, 0x0e41);
+    assert_eq!(region.read::<u16>(0), 0x0e41);
+    drop(region);
+
+    let region = map_mmio(PhysAddr::new(0xb8000), 16, CacheMode::Uncacheable)
+        .expect("map_mmio failed");
+    assert_eq!(region.virt_addr(), base);
+}
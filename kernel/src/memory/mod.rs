@@ -0,0 +1,259 @@
+pub mod mmio;
+pub mod dma;
+pub mod inspect;
+pub mod address_space;
+pub mod memblock;
+
+use x86_64::{
+    structures::paging::{PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator, FrameDeallocator, PageTableFlags},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::frame::PhysFrameRange,
+    VirtAddr,
+    PhysAddr,
+    align_up,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+lazy_static!{
+    pub static ref PAGE_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = {
+        Mutex::new(None)
+    };
+}
+
+lazy_static!{
+    pub static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = {
+        Mutex::new(None)
+    };
+}
+
+// user mappings live in PML4 entries 1 to 127, everything else is kernel space
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+pub const DMA_ZONE_SIZE: u64 = 4 * 1024 * 1024;
+// keep clear of the first megabyte, which firmware likes to use
+const DMA_ZONE_MIN: u64 = 0x10_0000;
+const DMA_ZONE_MAX: u64 = 0x1_0000_0000;
+
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+    dma_zone: Option<PhysFrameRange>,
+    // taken by memblock during early boot
+    reserved: memblock::Reserved,
+    // freed frames, linked through their first word
+    recycled: Option<PhysFrame>,
+    recycled_count: usize,
+    // extra references of every frame by frame number, nonzero for frames
+    // mapped more than once. Taken from memblock, so that page faults
+    // never allocate to count references.
+    shared: &'static mut [u16],
+    shared_count: usize,
+}
+
+impl BootInfoFrameAllocator {
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let frames = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.end_addr() / 4096)
+            .max()
+            .unwrap_or(0) as usize;
+        let size = (frames * core::mem::size_of::<u16>()) as u64;
+        // memblock stops with freeze, take the table before
+        let shared = memblock::alloc(size, 4096)
+            .expect("no memory for frame reference counts");
+        let shared = core::slice::from_raw_parts_mut(phys_to_virt(shared).as_mut_ptr(), frames);
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+            dma_zone: Self::find_dma_zone(memory_map),
+            reserved: memblock::freeze(),
+            recycled: None,
+            recycled_count: 0,
+            shared,
+            shared_count: 0,
+        }
+    }
+
+    // the first contiguous usable range below 4GiB big enough for the DMA zone
+    fn find_dma_zone(memory_map: &'static MemoryMap) -> Option<PhysFrameRange> {
+        memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .find_map(|r| {
+                let start = align_up(r.range.start_addr().max(DMA_ZONE_MIN), 4096);
+                let end = r.range.end_addr().min(DMA_ZONE_MAX);
+                if end >= start + DMA_ZONE_SIZE {
+                    let start = PhysFrame::containing_address(PhysAddr::new(start));
+                    Some(PhysFrame::range(start, start + DMA_ZONE_SIZE / 4096))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// frames set aside for `dma`, never handed out by this allocator
+    pub fn dma_zone(&self) -> Option<PhysFrameRange> {
+        self.dma_zone
+    }
+
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        let dma_zone = self.dma_zone;
+        let reserved = self.reserved;
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .filter(move |frame| match dma_zone {
+                Some(zone) => *frame < zone.start || *frame >= zone.end,
+                None => true,
+            })
+            .filter(move |frame| !reserved.contains(*frame))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.recycled {
+            let next: *const u64 = phys_to_virt(frame.start_address()).as_ptr();
+            self.recycled = match unsafe { next.read() } {
+                0 => None,
+                addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+            };
+            self.recycled_count -= 1;
+            return Some(frame);
+        }
+
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub usable: usize,
+    pub allocated: usize,
+    pub recycled: usize,
+    pub shared: usize,
+}
+
+impl BootInfoFrameAllocator {
+    pub fn frame_stats(&self) -> FrameStats {
+        let usable = self.usable_frames().count();
+        FrameStats {
+            usable,
+            allocated: self.next.min(usable) - self.recycled_count,
+            recycled: self.recycled_count,
+            shared: self.shared_count,
+        }
+    }
+
+    fn frame_number(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / 4096) as usize
+    }
+
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        self.shared.get(Self::frame_number(frame)).map_or(1, |&extra| extra as usize + 1)
+    }
+
+    /// add a reference to an allocated frame
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let extra = &mut self.shared[Self::frame_number(frame)];
+        if *extra == 0 {
+            self.shared_count += 1;
+        }
+        *extra = extra.checked_add(1).expect("too many references to a frame");
+    }
+
+    /// drop a reference to `frame` and free it with the last one,
+    /// returns whether it was freed.
+    pub unsafe fn release_frame(&mut self, frame: PhysFrame) -> bool {
+        match self.shared.get_mut(Self::frame_number(frame)) {
+            Some(extra) if *extra > 0 => {
+                *extra -= 1;
+                if *extra == 0 {
+                    self.shared_count -= 1;
+                }
+                false
+            }
+            _ => {
+                self.deallocate_frame(frame);
+                true
+            }
+        }
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next: *mut u64 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        next.write(self.recycled.map_or(0, |f| f.start_address().as_u64()));
+        self.recycled = Some(frame);
+        self.recycled_count += 1;
+    }
+}
+
+/// allocate a frame, running the reclaim hooks once before giving up
+pub fn try_allocate_frame() -> Option<PhysFrame> {
+    let frame = PAGE_ALLOCATOR.lock().as_mut()?.allocate_frame();
+    if frame.is_none() && crate::oom::reclaim() > 0 {
+        return PAGE_ALLOCATOR.lock().as_mut()?.allocate_frame();
+    }
+    frame
+}
+
+/// flags for writable, non-executable kernel data pages
+pub fn data_page_flags() -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        flags | PageTableFlags::NO_EXECUTE
+    } else {
+        flags
+    }
+}
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+static KERNEL_ROOT: AtomicU64 = AtomicU64::new(0);
+
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// where `addr` can be reached through the physical memory window
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// the PML4 the kernel booted with, which `MAPPER` edits
+pub fn kernel_root() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_ROOT.load(Ordering::Relaxed)))
+}
+
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_ROOT.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let physical_mem = level_4_table_frame.start_address();
+    let virtual_mem = physical_memory_offset + physical_mem.as_u64();
+    let page_table_ptr: *mut PageTable = virtual_mem.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
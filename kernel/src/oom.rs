@@ -0,0 +1,137 @@
+use crate::{allocator, memory::PAGE_ALLOCATOR, serial_println, task};
+use alloc::alloc::Layout;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+
+/// frees memory held by a subsystem and returns how many bytes came back.
+/// Hooks run when an allocation fails, so they must not allocate and must
+/// only ever try_lock, the failing allocation may hold any lock.
+pub type ReclaimHook = fn() -> usize;
+
+const MAX_HOOKS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Hook {
+    name: &'static str,
+    reclaim: ReclaimHook,
+    reclaimed: usize,
+}
+
+static HOOKS: Mutex<[Option<Hook>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+pub fn register_reclaim_hook(name: &'static str, reclaim: ReclaimHook) -> Result<(), ()> {
+    let mut hooks = HOOKS.lock();
+    let slot = hooks.iter_mut().find(|hook| hook.is_none()).ok_or(())?;
+    *slot = Some(Hook { name, reclaim, reclaimed: 0 });
+    Ok(())
+}
+
+/// run every reclaim hook, returns the bytes they freed together
+pub fn reclaim() -> usize {
+    // a hook failing to allocate would end up here again
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    let mut total = 0;
+    if let Some(mut hooks) = HOOKS.try_lock() {
+        for hook in hooks.iter_mut().flatten() {
+            let reclaimed = (hook.reclaim)();
+            hook.reclaimed += reclaimed;
+            total += reclaimed;
+        }
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    total
+}
+
+// frames kept back for page faults of a task that is about to be killed
+const RESERVE_FRAMES: usize = 8;
+
+static RESERVE: Mutex<[Option<PhysFrame>; RESERVE_FRAMES]> = Mutex::new([None; RESERVE_FRAMES]);
+
+/// set frames aside for tasks that run out of them, after the frame allocator
+pub fn init() {
+    refill_reserve();
+}
+
+/// take back what killed tasks used from the reserve, if frames are left
+pub fn refill_reserve() {
+    let mut reserve = match RESERVE.try_lock() {
+        Some(reserve) => reserve,
+        None => return,
+    };
+    for slot in reserve.iter_mut().filter(|slot| slot.is_none()) {
+        *slot = match PAGE_ALLOCATOR.try_lock() {
+            Some(mut frame_allocator) => frame_allocator.as_mut().and_then(|f| f.allocate_frame()),
+            None => None,
+        };
+        if slot.is_none() {
+            break;
+        }
+    }
+}
+
+/// a frame for a task whose page fault found none, it is killed after the fault
+pub fn take_reserve_frame() -> Option<PhysFrame> {
+    RESERVE.try_lock()?.iter_mut().find_map(|slot| slot.take())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutOfMemory {
+    Heap(Layout),
+    Frames,
+}
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutOfMemory::Heap(layout) => write!(f, "heap allocation of {} bytes aligned to {} failed ({})",
+                                                layout.size(), layout.align(),
+                                                allocator::failure_reason(layout)),
+            OutOfMemory::Frames => write!(f, "no physical frames left"),
+        }
+    }
+}
+
+pub fn print_memory_report() {
+    allocator::print_heap_report();
+    match PAGE_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => if let Some(frame_allocator) = frame_allocator.as_ref() {
+            let stats = frame_allocator.frame_stats();
+            serial_println!("frames: {} usable, {} allocated, {} recycled, {} shared",
+                            stats.usable, stats.allocated, stats.recycled, stats.shared);
+        },
+        None => serial_println!("frames: allocator busy"),
+    }
+    if let Some(hooks) = HOOKS.try_lock() {
+        for hook in hooks.iter().flatten() {
+            serial_println!("reclaim hook {}: {} bytes freed so far", hook.name, hook.reclaimed);
+        }
+    }
+}
+
+pub fn report(cause: OutOfMemory) {
+    serial_println!("OUT OF MEMORY: {}", cause);
+    print_memory_report();
+}
+
+/// a task ran out of memory, have the executor drop it when its poll returns.
+/// Tasks call this when a try_ allocation fails and they can't go on.
+pub fn kill_task(cause: OutOfMemory) {
+    report(cause);
+    if !task::kill_current() {
+        panic!("out of memory outside of a task: {}", cause);
+    }
+}
+
+/// memory ran out where there is no caller to hand the failure to,
+/// code that can recover uses the try_ allocation APIs instead.
+pub fn out_of_memory(cause: OutOfMemory) -> ! {
+    report(cause);
+    panic!("out of memory: {}", cause);
+}
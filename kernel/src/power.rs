@@ -0,0 +1,233 @@
+use crate::{
+    acpi::{self, AcpiError, Fadt, GenericAddress, Sdt, Signature},
+    klogln,
+    memory::{inspect, phys_to_virt},
+    utils::hlt_loop,
+};
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    PhysAddr,
+};
+
+// AML opcodes seen around the \_S5 package
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const ROOT_PREFIX: u8 = b'\\';
+
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_TYPE_MASK: u16 = 0b111 << PM1_SLEEP_TYPE_SHIFT;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    NoAcpi,
+    NoFadt,
+    Dsdt(AcpiError),
+    NoS5,
+    AcpiEnableTimeout,
+    StillRunning,
+}
+
+/// the values for SLP_TYPa and SLP_TYPb
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType(pub u8, pub u8);
+
+// a small integer as AML encodes it, and its length
+fn aml_integer(aml: &[u8]) -> Option<(u8, usize)> {
+    match *aml.get(0)? {
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        BYTE_PREFIX => Some((*aml.get(1)?, 2)),
+        _ => None,
+    }
+}
+
+/// find `Name (\_S5, Package () { a, b, ... })` in `aml` without
+/// interpreting it, which is all firmware needs for a soft off
+pub fn find_s5(aml: &[u8]) -> Option<SleepType> {
+    let position = aml.windows(4).enumerate()
+        .filter(|&(_, name)| name == b"_S5_")
+        .map(|(i, _)| i)
+        .find(|&i| {
+            // a definition, not a reference to the object
+            (i >= 1 && aml[i - 1] == NAME_OP)
+                || (i >= 2 && aml[i - 2] == NAME_OP && aml[i - 1] == ROOT_PREFIX)
+        })?;
+
+    let package = &aml[position + 4..];
+    if *package.get(0)? != PACKAGE_OP {
+        return None;
+    }
+    // the two top bits of PkgLength count the bytes following the first
+    let length_bytes = (*package.get(1)? >> 6) as usize + 1;
+    // then NumElements
+    let elements = package.get(1 + length_bytes + 1..)?;
+    let (a, used) = aml_integer(elements)?;
+    let (b, _) = aml_integer(&elements[used..])?;
+    Some(SleepType(a, b))
+}
+
+fn sleep_type_s5(fadt: &Fadt) -> Result<SleepType, PowerError> {
+    let dsdt = Sdt::at(fadt.dsdt).map_err(PowerError::Dsdt)?;
+    if let Some(sleep_type) = find_s5(dsdt.data()) {
+        return Ok(sleep_type);
+    }
+    acpi::tables().into_iter()
+        .flat_map(|acpi| acpi.tables.iter())
+        .filter(|sdt| sdt.signature == Signature(*b"SSDT"))
+        .find_map(|sdt| find_s5(sdt.data()))
+        .ok_or(PowerError::NoS5)
+}
+
+// each write to the POST port takes about a microsecond
+fn delay(microseconds: usize) {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..microseconds {
+        unsafe { port.write(0) };
+    }
+}
+
+// firmware in legacy mode only hands the PM registers over on request
+fn enable_acpi_mode(fadt: &Fadt) -> Result<(), PowerError> {
+    let mut control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 || unsafe { control.read() } & PM1_SCI_ENABLE != 0 {
+        return Ok(());
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    for _ in 0..300 {
+        if unsafe { control.read() } & PM1_SCI_ENABLE != 0 {
+            return Ok(());
+        }
+        delay(1000);
+    }
+    Err(PowerError::AcpiEnableTimeout)
+}
+
+unsafe fn enter_sleep_state(block: u32, sleep_type: u8) {
+    if block == 0 {
+        return;
+    }
+    let mut control: Port<u16> = Port::new(block as u16);
+    let value = control.read() & !PM1_SLEEP_TYPE_MASK;
+    control.write(value | (sleep_type as u16) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+}
+
+fn acpi_shutdown() -> PowerError {
+    let fadt = match acpi::tables() {
+        Some(acpi) => match acpi.fadt.as_ref() {
+            Some(fadt) => fadt,
+            None => return PowerError::NoFadt,
+        },
+        None => return PowerError::NoAcpi,
+    };
+    let sleep_type = match sleep_type_s5(fadt) {
+        Ok(sleep_type) => sleep_type,
+        Err(e) => return e,
+    };
+    if let Err(e) = enable_acpi_mode(fadt) {
+        return e;
+    }
+
+    unsafe {
+        enter_sleep_state(fadt.pm1a_control_block, sleep_type.0);
+        enter_sleep_state(fadt.pm1b_control_block, sleep_type.1);
+    }
+    delay(100_000);
+    PowerError::StillRunning
+}
+
+/// switch the machine off through ACPI, or halt it for good when that fails
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    klogln!("power: shutting down");
+    let e = acpi_shutdown();
+    klogln!("power: shutdown failed: {:?}, halting", e);
+    hlt_loop();
+}
+
+unsafe fn write_reset_register(register: GenericAddress, value: u8) {
+    match register.space {
+        GenericAddress::SPACE_IO => Port::<u8>::new(register.address as u16).write(value),
+        // mapping it would take the mapper and frame allocator locks, which a
+        // panicking reboot may hold. The physical window maps it write-back,
+        // but the MTRRs keep device memory uncacheable.
+        GenericAddress::SPACE_MEMORY => {
+            let phys = PhysAddr::new(register.address);
+            let virt = phys_to_virt(phys);
+            if inspect::translate(virt) == Some(phys) {
+                core::ptr::write_volatile(virt.as_mut_ptr::<u8>(), value);
+            }
+        }
+        // PCI configuration space of a function on bus 0
+        GenericAddress::SPACE_PCI_CONFIG => {
+            let device = (register.address >> 32 & 0x1f) as u32;
+            let function = (register.address >> 16 & 0x7) as u32;
+            let offset = (register.address & 0xff) as u32;
+            Port::<u32>::new(PCI_CONFIG_ADDRESS)
+                .write(1 << 31 | device << 11 | function << 8 | offset & 0xfc);
+            Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(value);
+        }
+        _ => {}
+    }
+}
+
+// ask the keyboard controller to pulse the reset line
+unsafe fn pulse_8042() {
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    for _ in 0..1000 {
+        if status.read() & KBC_INPUT_FULL == 0 {
+            break;
+        }
+        delay(10);
+    }
+    status.write(KBC_PULSE_RESET);
+}
+
+// with an empty IDT any exception ends in a triple fault
+unsafe fn triple_fault() {
+    let empty_idt = [0u64; 2];
+    asm!("lidt [{}]\nint3", in(reg) empty_idt.as_ptr(), options(nostack));
+}
+
+/// reset the machine, trying the ACPI reset register, the keyboard
+/// controller and at last a triple fault
+pub fn reboot() -> ! {
+    interrupts::disable();
+    klogln!("power: rebooting");
+    let fadt = acpi::tables().and_then(|acpi| acpi.fadt.as_ref());
+
+    unsafe {
+        if let Some((register, value)) = fadt.and_then(|fadt| fadt.reset) {
+            write_reset_register(register, value);
+            delay(100_000);
+        }
+        if fadt.map_or(true, Fadt::has_8042) {
+            pulse_8042();
+            delay(100_000);
+        }
+        triple_fault();
+    }
+    hlt_loop();
+}
+
+#[test_case]
+fn test_find_s5() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero }) as iasl emits it
+    let aml = [0x10, 0x0a, b'_', b'S', b'4', b'_',
+               NAME_OP, ROOT_PREFIX, b'_', b'S', b'5', b'_',
+               PACKAGE_OP, 0x06, 0x04, BYTE_PREFIX, 0x05, ZERO_OP, ZERO_OP, ZERO_OP];
+    assert_eq!(find_s5(&aml), Some(SleepType(5, 0)));
+    // a reference to the object is not its definition
+    assert_eq!(find_s5(&aml[8..]), None);
+}
@@ -0,0 +1,338 @@
+use crate::{acpi::{checksum, phys_bytes}, klogln};
+use alloc::{string::String, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{fmt, ops::Range, str};
+use x86_64::PhysAddr;
+
+const ANCHOR_V2: &[u8] = b"_SM_";
+const ANCHOR_V3: &[u8] = b"_SM3_";
+const BIOS_AREA: Range<u64> = 0xf_0000..0x10_0000;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+const PROCESSOR_POPULATED: u8 = 1 << 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmbiosError {
+    NoEntryPoint,
+    BadEntryPoint,
+    AlreadyInitialized,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+struct EntryPoint {
+    version: (u8, u8),
+    table: PhysAddr,
+    length: usize,
+}
+
+fn parse_entry_point(address: PhysAddr) -> Option<EntryPoint> {
+    let header = unsafe { phys_bytes(address, 0x20) };
+    if header.starts_with(ANCHOR_V3) {
+        let bytes = &header[..(header[6] as usize).min(header.len())];
+        if bytes.len() < 0x18 || !checksum(bytes) {
+            return None;
+        }
+        let mut table = [0; 8];
+        table.copy_from_slice(&bytes[0x10..0x18]);
+        Some(EntryPoint {
+            version: (bytes[7], bytes[8]),
+            table: PhysAddr::new(u64::from_le_bytes(table)),
+            // only an upper bound, the table ends with its end structure
+            length: u32_at(bytes, 0x0c) as usize,
+        })
+    } else if header.starts_with(ANCHOR_V2) {
+        let bytes = &header[..(header[5] as usize).min(header.len())];
+        if bytes.len() < 0x1f || !checksum(bytes) || &bytes[0x10..0x15] != b"_DMI_" {
+            return None;
+        }
+        Some(EntryPoint {
+            version: (bytes[6], bytes[7]),
+            table: PhysAddr::new(u32_at(bytes, 0x18) as u64),
+            length: u16_at(bytes, 0x16) as usize,
+        })
+    } else {
+        None
+    }
+}
+
+// the entry point is on a 16 byte boundary in the BIOS area. Firmware may
+// provide both, the 64 bit one describes the table without 32 bit limits.
+fn scan_for_entry_point() -> Option<PhysAddr> {
+    let valid = || BIOS_AREA.step_by(16)
+        .map(PhysAddr::new)
+        .filter(|&address| parse_entry_point(address).is_some());
+    valid().find(|&address| unsafe { phys_bytes(address, ANCHOR_V3.len()) } == ANCHOR_V3)
+        .or_else(|| valid().next())
+}
+
+/// one structure of the table, with its formatted area and its strings
+#[derive(Clone, Copy)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    formatted: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    // offsets are from the start of the structure, as in the specification.
+    // Fields that an older version does not have read as 0.
+
+    pub fn byte(&self, offset: usize) -> u8 {
+        self.formatted.get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn word(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.byte(offset), self.byte(offset + 1)])
+    }
+
+    pub fn dword(&self, offset: usize) -> u32 {
+        self.word(offset) as u32 | (self.word(offset + 2) as u32) << 16
+    }
+
+    /// the string the byte at `offset` refers to, None for no string
+    pub fn string(&self, offset: usize) -> Option<String> {
+        let index = self.byte(offset) as usize;
+        if index == 0 {
+            return None;
+        }
+        let bytes = self.strings.split(|&b| b == 0).nth(index - 1)?;
+        Some(String::from_utf8_lossy(bytes).trim().into())
+    }
+}
+
+/// walk the structures of a table up to the end structure
+pub struct Structures<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Structure<'a>> {
+        let table = self.table;
+        if table.len() < 4 {
+            return None;
+        }
+        let length = table[1] as usize;
+        if length < 4 || length > table.len() {
+            return None;
+        }
+        // the string set ends with two zero bytes, also when it is empty
+        let end = (length..table.len().saturating_sub(1))
+            .find(|&i| table[i] == 0 && table[i + 1] == 0)?;
+        let structure = Structure {
+            kind: table[0],
+            handle: u16_at(table, 2),
+            formatted: &table[..length],
+            strings: &table[length..end],
+        };
+        self.table = if structure.kind == TYPE_END { &[] } else { &table[end + 2..] };
+        Some(structure)
+    }
+}
+
+pub fn structures(table: &[u8]) -> Structures {
+    Structures { table }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BiosInfo {
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    pub release_date: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemInfo {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    pub uuid: [u8; 16],
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BaseboardInfo {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessorInfo {
+    pub socket: Option<String>,
+    pub manufacturer: Option<String>,
+    pub version: Option<String>,
+    pub populated: bool,
+    /// in MHz, 0 when unknown
+    pub max_speed: u16,
+    pub current_speed: u16,
+    pub cores: u8,
+    pub threads: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDevice {
+    pub locator: Option<String>,
+    pub bank: Option<String>,
+    pub manufacturer: Option<String>,
+    pub part_number: Option<String>,
+    /// in bytes, 0 for an empty slot
+    pub size: u64,
+    /// in MT/s, 0 when unknown
+    pub speed: u16,
+}
+
+impl MemoryDevice {
+    fn size(structure: &Structure) -> u64 {
+        match structure.word(0x0c) {
+            0 | 0xffff => 0,
+            // the real size is in the extended field, in MiB
+            0x7fff => (structure.dword(0x1c) as u64 & 0x7fff_ffff) << 20,
+            size if size & 0x8000 != 0 => ((size & 0x7fff) as u64) << 10,
+            size => (size as u64) << 20,
+        }
+    }
+}
+
+/// what SMBIOS says about the machine
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    pub version: (u8, u8),
+    pub bios: Option<BiosInfo>,
+    pub system: Option<SystemInfo>,
+    pub baseboard: Option<BaseboardInfo>,
+    pub processors: Vec<ProcessorInfo>,
+    pub memory_devices: Vec<MemoryDevice>,
+}
+
+impl Inventory {
+    pub fn parse(version: (u8, u8), table: &[u8]) -> Inventory {
+        let mut inventory = Inventory { version, ..Inventory::default() };
+        for s in structures(table) {
+            match s.kind {
+                TYPE_BIOS => inventory.bios = Some(BiosInfo {
+                    vendor: s.string(0x04),
+                    version: s.string(0x05),
+                    release_date: s.string(0x08),
+                }),
+                TYPE_SYSTEM => {
+                    let mut uuid = [0; 16];
+                    for (i, byte) in uuid.iter_mut().enumerate() {
+                        *byte = s.byte(0x08 + i);
+                    }
+                    inventory.system = Some(SystemInfo {
+                        manufacturer: s.string(0x04),
+                        product: s.string(0x05),
+                        version: s.string(0x06),
+                        serial: s.string(0x07),
+                        uuid,
+                    });
+                }
+                TYPE_BASEBOARD => inventory.baseboard = Some(BaseboardInfo {
+                    manufacturer: s.string(0x04),
+                    product: s.string(0x05),
+                    version: s.string(0x06),
+                    serial: s.string(0x07),
+                }),
+                TYPE_PROCESSOR => inventory.processors.push(ProcessorInfo {
+                    socket: s.string(0x04),
+                    manufacturer: s.string(0x07),
+                    version: s.string(0x10),
+                    populated: s.byte(0x18) & PROCESSOR_POPULATED != 0,
+                    max_speed: s.word(0x14),
+                    current_speed: s.word(0x16),
+                    cores: s.byte(0x23),
+                    threads: s.byte(0x25),
+                }),
+                TYPE_MEMORY_DEVICE => inventory.memory_devices.push(MemoryDevice {
+                    locator: s.string(0x10),
+                    bank: s.string(0x11),
+                    manufacturer: s.string(0x17),
+                    part_number: s.string(0x1a),
+                    size: MemoryDevice::size(&s),
+                    speed: s.word(0x15),
+                }),
+                _ => {}
+            }
+        }
+        inventory
+    }
+
+    /// memory installed in all slots, in bytes
+    pub fn installed_memory(&self) -> u64 {
+        self.memory_devices.iter().map(|device| device.size).sum()
+    }
+}
+
+/// the one line summary printed at boot
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = || String::from("unknown");
+        let system = self.system.clone().unwrap_or_default();
+        write!(f, "{} {}", system.manufacturer.unwrap_or_else(unknown),
+               system.product.unwrap_or_else(unknown))?;
+        let sockets = self.processors.iter().filter(|cpu| cpu.populated).count();
+        let slots = self.memory_devices.iter().filter(|device| device.size != 0).count();
+        write!(f, ", {} CPU socket(s), {} MiB in {} slot(s)", sockets,
+               self.installed_memory() >> 20, slots)?;
+        if let Some(bios) = &self.bios {
+            write!(f, ", BIOS {} {}", bios.vendor.clone().unwrap_or_else(unknown),
+                   bios.version.clone().unwrap_or_else(unknown))?;
+        }
+        write!(f, ", SMBIOS {}.{}", self.version.0, self.version.1)
+    }
+}
+
+static INVENTORY: OnceCell<Inventory> = OnceCell::uninit();
+
+/// the inventory built by `init`
+pub fn inventory() -> Option<&'static Inventory> {
+    INVENTORY.try_get().ok()
+}
+
+/// decode the structure table of the entry point at `entry_point`, or of
+/// one found in the BIOS area when the boot loader did not pass it
+pub fn init(entry_point: Option<PhysAddr>) -> Result<&'static Inventory, SmbiosError> {
+    let entry_point = match entry_point.or_else(scan_for_entry_point) {
+        Some(address) => parse_entry_point(address).ok_or(SmbiosError::BadEntryPoint)?,
+        None => return Err(SmbiosError::NoEntryPoint),
+    };
+    let table = unsafe { phys_bytes(entry_point.table, entry_point.length) };
+    let inventory = Inventory::parse(entry_point.version, table);
+    klogln!("smbios: {}", inventory);
+
+    INVENTORY.try_init_once(|| inventory).map_err(|_| SmbiosError::AlreadyInitialized)?;
+    Ok(INVENTORY.try_get().unwrap())
+}
+
+#[test_case]
+fn test_parse_structures() {
+    let table = [
+        TYPE_SYSTEM, 0x08, 0x01, 0x00, 0x01, 0x02, 0x00, 0x00,
+        b'A', b'c', b'm', b'e', 0, b'B', b'o', b'x', 0, 0,
+        TYPE_MEMORY_DEVICE, 0x10, 0x02, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x02, 0, 0, 0, 0,
+        TYPE_END, 0x04, 0xff, 0xff, 0, 0,
+    ];
+    assert_eq!(structures(&table).count(), 3);
+    let inventory = Inventory::parse((2, 8), &table);
+    let system = inventory.system.as_ref().unwrap();
+    assert_eq!(system.product.as_deref(), Some("Box"));
+    assert_eq!(system.version, None);
+    assert_eq!(inventory.installed_memory(), 512 << 20);
+}
@@ -75,6 +75,8 @@ impl Executor {
             }
 
             self.run_ready_tasks();
+            // frames may be free again after tasks were killed
+            crate::oom::refill_reserve();
             self.sleep_if_idle();
         }
     }
@@ -4,7 +4,12 @@ use core::{pin::Pin, task::{Poll, Context}};
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use crate::{println, print};
+use x86_64::instructions::port::Port;
+use crate::{println, print, irq::{self, HandlerId, IrqError, IrqReturn, Sharing}};
+
+// the PS/2 controller, which raises IRQ1 for the first port
+const DATA_PORT: u16 = 0x60;
+const KEYBOARD_IRQ: u8 = 1;
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 
@@ -42,7 +47,7 @@ impl Stream for ScancodeStream {
     }
 }
 
-pub(crate) fn add_scancode(scancode: u8) {
+fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
             println!("WARNING: scancode queue full; dropping keyboard input");
@@ -54,6 +59,15 @@ pub(crate) fn add_scancode(scancode: u8) {
     }
 }
 
+/// queue what the keyboard sends from its interrupt on
+pub fn init() -> Result<HandlerId, IrqError> {
+    irq::register_irq(KEYBOARD_IRQ, "keyboard", Sharing::Exclusive, || {
+        let scancode: u8 = unsafe { Port::new(DATA_PORT).read() };
+        add_scancode(scancode);
+        IrqReturn::Handled
+    })
+}
+
 pub async fn print_keypress() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1,
@@ -1,10 +1,11 @@
 pub mod executor;
 pub mod keyboard;
+pub mod timer;
 
 use core::{future::Future, pin::Pin};
 use alloc::boxed::Box;
 use core::task::{Context, Poll};
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -16,6 +17,27 @@ impl TaskId {
     }
 }
 
+const NO_TASK: u64 = u64::MAX;
+
+// the task the executor is polling, NO_TASK between polls
+static CURRENT: AtomicU64 = AtomicU64::new(NO_TASK);
+static KILLED: AtomicBool = AtomicBool::new(false);
+
+/// whether a task is being polled right now
+pub fn in_task() -> bool {
+    CURRENT.load(Ordering::Relaxed) != NO_TASK
+}
+
+/// have the executor drop the running task as soon as its poll returns,
+/// returns false when no task is running
+pub fn kill_current() -> bool {
+    if !in_task() {
+        return false;
+    }
+    KILLED.store(true, Ordering::Relaxed);
+    true
+}
+
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()> + Send>>,
@@ -29,7 +51,34 @@ impl Task {
         }
     }
 
+    // Ready also when the task was killed while it ran
     fn poll(&mut self, cx: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(cx)
+        CURRENT.store(self.id.0, Ordering::Relaxed);
+        let poll = self.future.as_mut().poll(cx);
+        CURRENT.store(NO_TASK, Ordering::Relaxed);
+        if KILLED.swap(false, Ordering::Relaxed) {
+            crate::klogln!("task {} killed", self.id.0);
+            return Poll::Ready(());
+        }
+        poll
+    }
+}
+#[test_case]
+fn test_killed_task_is_dropped() {
+    use alloc::{sync::Arc, task::Wake};
+    use core::task::Waker;
+
+    struct Noop;
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
     }
-}
\ No newline at end of file
+
+    assert!(!kill_current());
+    let mut task = Task::new(futures_util::future::poll_fn(|_| {
+        assert!(kill_current());
+        Poll::<()>::Pending
+    }));
+    let waker = Waker::from(Arc::new(Noop));
+    assert_eq!(task.poll(&mut Context::from_waker(&waker)), Poll::Ready(()));
+    assert!(!in_task());
+}
@@ -0,0 +1,331 @@
+use crate::time::{self, Duration, Instant};
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// one slot per tick, a timer further out waits for its round
+const SLOTS: usize = 256;
+
+struct Entry {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+    fired: bool,
+}
+
+struct Wheel {
+    slots: [Vec<Entry>; SLOTS],
+    // the last tick whose slot was looked at
+    expired: u64,
+}
+
+const EMPTY_SLOT: Vec<Entry> = Vec::new();
+
+// taken with interrupts disabled outside of the timer interrupt
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    slots: [EMPTY_SLOT; SLOTS],
+    expired: 0,
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+struct TimerId {
+    id: u64,
+    slot: usize,
+}
+
+impl Wheel {
+    fn insert(&mut self, deadline: u64, waker: Waker) -> TimerId {
+        // a deadline the wheel already went past would wait a whole round
+        let deadline = deadline.max(self.expired + 1);
+        let timer = TimerId {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            slot: deadline as usize % SLOTS,
+        };
+        self.slots[timer.slot].push(Entry { id: timer.id, deadline, waker, fired: false });
+        timer
+    }
+
+    fn entry(&mut self, timer: TimerId) -> Option<&mut Entry> {
+        self.slots[timer.slot].iter_mut().find(|entry| entry.id == timer.id)
+    }
+
+    fn remove(&mut self, timer: TimerId) -> Option<Entry> {
+        let slot = &mut self.slots[timer.slot];
+        let index = slot.iter().position(|entry| entry.id == timer.id)?;
+        Some(slot.swap_remove(index))
+    }
+}
+
+/// wake the timers that are due, called by the timer interrupt.
+///
+/// Nothing is freed here, a woken timer is removed by its future.
+pub fn expire() {
+    let now = time::ticks();
+    // the lock is only held with interrupts disabled, but an NMI or a
+    // nested handler could see it taken. The next tick catches up.
+    let mut wheel = match WHEEL.try_lock() {
+        Some(wheel) => wheel,
+        None => return,
+    };
+    let first = wheel.expired.max(now.saturating_sub(SLOTS as u64 - 1));
+    for tick in first + 1..=now {
+        for entry in wheel.slots[tick as usize % SLOTS].iter_mut() {
+            if !entry.fired && entry.deadline <= now {
+                entry.fired = true;
+                entry.waker.wake_by_ref();
+            }
+        }
+    }
+    wheel.expired = wheel.expired.max(now);
+}
+
+/// timers waiting in the wheel
+pub fn pending() -> usize {
+    without_interrupts(|| WHEEL.lock().slots.iter().map(|slot| slot.len()).sum())
+}
+
+// the tick at which `deadline` has certainly passed, one more than
+// rounding up because the current tick is partly over
+fn deadline_tick(deadline: Instant) -> u64 {
+    let rate = time::tick_rate() as u128;
+    let left = deadline.duration_since(Instant::now()).as_nanos();
+    time::ticks() + ((left * rate + 999_999_999) / 1_000_000_000) as u64 + 1
+}
+
+/// a future that completes at a deadline
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<TimerId>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// wait for a new deadline instead
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            // the waker may be the last reference to its task, dropped here and not in the wheel
+            let entry = without_interrupts(|| WHEEL.lock().remove(timer));
+            drop(entry);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.is_elapsed() {
+            self.cancel();
+            return Poll::Ready(());
+        }
+
+        let deadline = deadline_tick(self.deadline);
+        let timer = self.timer;
+        let timer = without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            if let Some(entry) = timer.and_then(|timer| wheel.entry(timer)) {
+                if !entry.fired {
+                    if !entry.waker.will_wake(cx.waker()) {
+                        entry.waker = cx.waker().clone();
+                    }
+                    return timer;
+                }
+            }
+            // fired too early, the tick and the clock drift apart
+            if let Some(timer) = timer {
+                wheel.remove(timer);
+            }
+            Some(wheel.insert(deadline, cx.waker().clone()))
+        });
+        self.timer = timer;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, timer: None }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// the future of a `timeout` did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // the future is never moved out, Sleep is Unpin
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// run `future` for at most `duration`
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout { future, sleep: sleep(duration) }
+}
+
+/// a stream that yields every `period`, skipping the ticks it was too late for
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// wait for the next tick
+    pub async fn tick(&mut self) -> Instant {
+        futures_util::stream::StreamExt::next(self).await.unwrap()
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let deadline = this.sleep.deadline();
+                let now = Instant::now();
+                let mut next = deadline + this.period;
+                if next <= now {
+                    next = now + this.period;
+                }
+                this.sleep.reset(next);
+                Poll::Ready(Some(deadline))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// a stream of instants `period` apart, the first one right away
+pub fn interval(period: Duration) -> Interval {
+    assert!(period > Duration::from_secs(0), "interval period must not be zero");
+    Interval { period, sleep: sleep_until(Instant::now()) }
+}
+
+#[cfg(test)]
+struct Flag(core::sync::atomic::AtomicBool);
+
+#[cfg(test)]
+impl alloc::task::Wake for Flag {
+    fn wake(self: alloc::sync::Arc<Self>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_sleep_wakes() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut sleep = sleep(Duration::from_millis(5));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    assert_eq!(pending(), 1);
+
+    let start = Instant::now();
+    while !flag.0.load(Ordering::Relaxed) {
+        assert!(start.elapsed() < Duration::from_secs(1), "sleep never woke");
+        x86_64::instructions::hlt();
+    }
+    assert!(sleep.is_elapsed());
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Ready(()));
+    assert_eq!(pending(), 0);
+}
+
+#[test_case]
+fn test_timeout() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+    use futures_util::future;
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+
+    let mut completed = timeout(future::ready(7), Duration::from_millis(5));
+    assert_eq!(Pin::new(&mut completed).poll(&mut context), Poll::Ready(Ok(7)));
+
+    let mut elapsed = timeout(future::pending::<()>(), Duration::from_millis(5));
+    assert_eq!(Pin::new(&mut elapsed).poll(&mut context), Poll::Pending);
+    let start = Instant::now();
+    while !flag.0.load(Ordering::Relaxed) {
+        assert!(start.elapsed() < Duration::from_secs(1), "timeout never woke");
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(Pin::new(&mut elapsed).poll(&mut context), Poll::Ready(Err(Elapsed)));
+}
+
+#[test_case]
+fn test_interval_skips_missed_ticks() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    let period = Duration::from_millis(10);
+
+    let mut interval = interval(period);
+    let first = match Pin::new(&mut interval).poll_next(&mut context) {
+        Poll::Ready(Some(first)) => first,
+        _ => panic!("the first tick is not immediate"),
+    };
+    // miss the next three ticks, only the first of them is still yielded
+    time::busy_wait(period * 3 + period / 2);
+    let late = Instant::now();
+    assert_eq!(Pin::new(&mut interval).poll_next(&mut context), Poll::Ready(Some(first + period)));
+    assert_eq!(Pin::new(&mut interval).poll_next(&mut context), Poll::Pending);
+    assert!(interval.sleep.deadline() >= late + period);
+}
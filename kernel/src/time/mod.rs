@@ -0,0 +1,337 @@
+pub mod system;
+
+use crate::{
+    acpi,
+    driver::{
+        apic::{self, TimerDivide, TimerMode, APIC},
+        hpet::Hpet,
+        pit, rtc,
+    },
+    interrupts::InterruptIndex,
+    irq::{self, IrqReturn, Sharing},
+    klogln,
+};
+use conquer_once::spin::OnceCell;
+use core::{
+    convert::TryFrom,
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+};
+
+pub use core::time::Duration;
+pub use system::{DateTime, SystemTime};
+
+/// ticks per second unless the kernel asks for something else
+pub const DEFAULT_HZ: u32 = 1000;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// how long the TSC and the local APIC timer are measured for
+const CALIBRATION_MICROS: u64 = 10_000;
+
+/// what raises the timer interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    LocalApic,
+}
+
+/// what `Instant::now` reads, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CounterSource {
+    Tsc,
+    Hpet,
+    Ticks,
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_RATE: AtomicU32 = AtomicU32::new(0);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+static COUNTER: AtomicU8 = AtomicU8::new(CounterSource::Ticks as u8);
+// counter values at the time the clock started
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+// the counters are not in sync with each other, never go back in time
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// a point on the monotonic clock, which starts at `init`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(nanos())
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Instant {
+        Instant(nanos)
+    }
+
+    /// nanoseconds since the clock started
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// zero if `earlier` is in fact later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(u64::try_from(duration.as_nanos()).ok()?).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(u64::try_from(duration.as_nanos()).ok()?).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// called by the timer interrupt
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// timer interrupts since the clock started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// timer interrupts per second, 0 before `init`
+pub fn tick_rate() -> u32 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+pub fn tick_source() -> TickSource {
+    match TICK_SOURCE.load(Ordering::Relaxed) {
+        0 => TickSource::Pit,
+        _ => TickSource::LocalApic,
+    }
+}
+
+pub fn counter_source() -> CounterSource {
+    match COUNTER.load(Ordering::Relaxed) {
+        0 => CounterSource::Tsc,
+        1 => CounterSource::Hpet,
+        _ => CounterSource::Ticks,
+    }
+}
+
+/// in Hz, None when the TSC was not calibrated
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+fn raw_nanos() -> u64 {
+    match counter_source() {
+        CounterSource::Tsc => {
+            let counts = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+            (counts as u128 * NANOS_PER_SECOND as u128
+                / TSC_FREQUENCY.load(Ordering::Relaxed) as u128) as u64
+        }
+        CounterSource::Hpet => {
+            let hpet = HPET.try_get().unwrap();
+            hpet.nanos(hpet.counter().wrapping_sub(HPET_BASE.load(Ordering::Relaxed)))
+        }
+        CounterSource::Ticks => ticks() * TICK_NANOS.load(Ordering::Relaxed),
+    }
+}
+
+/// nanoseconds on the monotonic clock
+pub fn nanos() -> u64 {
+    let now = raw_nanos();
+    LAST_NANOS.fetch_max(now, Ordering::Relaxed).max(now)
+}
+
+/// time since the clock started
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanos())
+}
+
+/// spin for `duration`, which works with interrupts disabled
+pub fn busy_wait(duration: Duration) {
+    if counter_source() == CounterSource::Ticks {
+        pit::busy_wait(duration.as_micros() as u64);
+        return;
+    }
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+// TSC counts and local APIC timer counts, divided by 16, during
+// CALIBRATION_MICROS of the most precise reference there is
+fn calibrate(hpet: Option<&Hpet>) -> (u64, u64) {
+    let mut apic = APIC.lock();
+    let mut local = apic.as_mut().map(|apic| &mut apic.local);
+    if let Some(local) = local.as_mut() {
+        // masked, only the count matters
+        local.set_timer(InterruptIndex::Timer.as_u8(), TimerMode::OneShot, TimerDivide::By16, u32::max_value());
+        local.mask_timer();
+    }
+    let apic_start = local.as_ref().map_or(0, |local| local.timer_count());
+    let tsc_start = rdtsc();
+    match hpet {
+        Some(hpet) => {
+            let end = hpet.counter() + CALIBRATION_MICROS * 1_000_000_000 / hpet.period();
+            while hpet.counter() < end {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+        None => pit::busy_wait(CALIBRATION_MICROS),
+    }
+    let tsc = rdtsc() - tsc_start;
+    let apic_counts = local.as_mut().map_or(0, |local| {
+        let counts = apic_start - local.timer_count();
+        local.stop_timer();
+        counts
+    });
+
+    let scale = 1_000_000 / CALIBRATION_MICROS;
+    (tsc * scale, apic_counts as u64 * scale)
+}
+
+// the TSC runs at a constant rate in every power state
+fn invariant_tsc() -> bool {
+    let max_extended = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007
+        && unsafe { core::arch::x86_64::__cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// start the timer interrupt at `hz` and the monotonic clock. Interrupts
+/// must be disabled, and the APIC and ACPI set up if they are used.
+pub fn init(hz: u32) {
+    let hz = hz.max(1);
+    if let Some(info) = acpi::tables().and_then(|acpi| acpi.hpet.as_ref()) {
+        match Hpet::new(info.address) {
+            // a 32 bit counter wraps within minutes
+            Ok(hpet) if hpet.is_64bit() => {
+                HPET.try_init_once(|| hpet).ok();
+            }
+            Ok(_) => klogln!("time: HPET has a 32 bit counter, not used"),
+            Err(e) => klogln!("time: HPET: {:?}", e),
+        }
+    }
+    let hpet = HPET.try_get().ok();
+
+    let (tsc_hz, apic_hz) = calibrate(hpet);
+    let counter = if invariant_tsc() && tsc_hz != 0 {
+        CounterSource::Tsc
+    } else if hpet.is_some() {
+        CounterSource::Hpet
+    } else {
+        CounterSource::Ticks
+    };
+    TSC_FREQUENCY.store(tsc_hz, Ordering::Relaxed);
+
+    irq::register_vector(InterruptIndex::Timer.as_u8(), "timer", Sharing::Exclusive, || {
+        tick();
+        crate::task::timer::expire();
+        IrqReturn::Handled
+    }).expect("timer vector taken");
+    let (source, rate) = if apic::is_enabled() && apic_hz != 0 {
+        let mut apic = APIC.lock();
+        let apic = apic.as_mut().unwrap();
+        // the PIT still raises IRQ0, which nobody wants now
+        apic.mask_isa_irq(0);
+        let count = (apic_hz / hz as u64).max(1) as u32;
+        apic.local.set_timer(InterruptIndex::Timer.as_u8(), TimerMode::Periodic, TimerDivide::By16, count);
+        (TickSource::LocalApic, (apic_hz / count as u64) as u32)
+    } else {
+        let rate = pit::set_periodic(hz);
+        irq::enable_irq(0).expect("no route for the PIT");
+        (TickSource::Pit, rate)
+    };
+
+    TICK_RATE.store(rate, Ordering::Relaxed);
+    TICK_NANOS.store(NANOS_PER_SECOND / rate as u64, Ordering::Relaxed);
+    TICK_SOURCE.store(source as u8, Ordering::Relaxed);
+    TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+    if let Some(hpet) = hpet {
+        HPET_BASE.store(hpet.counter(), Ordering::Relaxed);
+    }
+    COUNTER.store(counter as u8, Ordering::Relaxed);
+
+    klogln!("time: {} Hz from the {:?}, clock from the {:?}, TSC at {} MHz",
+            rate, source, counter, tsc_hz / 1_000_000);
+}
+
+/// set the wall clock from the CMOS RTC
+pub fn init_wall_clock() {
+    let fadt = acpi::tables().and_then(|acpi| acpi.fadt.as_ref());
+    if !fadt.map_or(true, |fadt| fadt.has_cmos_rtc()) {
+        klogln!("time: no RTC, the wall clock starts at the epoch");
+        return;
+    }
+    if let Some(fadt) = fadt {
+        rtc::set_century_register(fadt.century);
+    }
+    let now = rtc::read();
+    system::set(now);
+    klogln!("time: wall clock set to {} UTC", now);
+}
+
+#[test_case]
+fn test_clock_advances() {
+    let start = Instant::now();
+    let start_ticks = ticks();
+    busy_wait(Duration::from_millis(20));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(20));
+    assert!(elapsed < Duration::from_secs(1));
+    assert!(ticks() > start_ticks);
+}
+
+#[test_case]
+fn test_instant_overflow() {
+    let now = Instant::now();
+    // more nanoseconds than fit in a u64
+    assert_eq!(now.checked_add(Duration::from_secs(u64::MAX)), None);
+    assert_eq!(now.checked_sub(Duration::from_secs(u64::MAX)), None);
+    assert_eq!(now.checked_add(Duration::from_secs(1)).map(|later| later - now),
+               Some(Duration::from_secs(1)));
+}
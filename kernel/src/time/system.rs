@@ -0,0 +1,161 @@
+use super::{nanos, Duration};
+use core::{
+    convert::TryFrom,
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// wall clock time at which the monotonic clock read 0, in ns since the epoch
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+static SET: AtomicBool = AtomicBool::new(false);
+
+/// a calendar date and time of day, in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// seconds since 1970-01-01, the date must not be before it
+    pub fn unix_timestamp(&self) -> u64 {
+        // days from the civil calendar, with years starting in March
+        let (year, month) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * SECONDS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(seconds: u64) -> DateTime {
+        let days = seconds / SECONDS_PER_DAY + 719_468;
+        let seconds = seconds % SECONDS_PER_DAY;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+            - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// wall clock time, the monotonic clock offset by the time it started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(u64);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+    /// counts from the epoch as if the machine booted then, until `set` is called
+    pub fn now() -> SystemTime {
+        SystemTime(BOOT_TIME.load(Ordering::Relaxed) + nanos())
+    }
+
+    /// None if `earlier` is later
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    pub fn unix_timestamp(&self) -> u64 {
+        self.0 / NANOS_PER_SECOND
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_timestamp(self.unix_timestamp())
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(u64::try_from(duration.as_nanos()).ok()?).map(SystemTime)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(u64::try_from(duration.as_nanos()).ok()?).map(SystemTime)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        self.checked_add(duration).expect("overflow when adding duration to system time")
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        self.checked_sub(duration).expect("overflow when subtracting duration from system time")
+    }
+}
+
+/// with milliseconds, `2020-07-01 12:00:00.000`
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:03}", self.date_time(), self.0 % NANOS_PER_SECOND / 1_000_000)
+    }
+}
+
+/// make the wall clock read `now`
+pub fn set(now: DateTime) {
+    let now = now.unix_timestamp() * NANOS_PER_SECOND;
+    BOOT_TIME.store(now.saturating_sub(nanos()), Ordering::Relaxed);
+    SET.store(true, Ordering::Relaxed);
+}
+
+/// whether the wall clock was set, before it counts from the epoch
+pub fn is_set() -> bool {
+    SET.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_date_time_conversion() {
+    let date = DateTime { year: 2020, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert_eq!(date.unix_timestamp(), 1_583_020_798);
+    assert_eq!(DateTime::from_unix_timestamp(1_583_020_798), date);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
+
+#[test_case]
+fn test_system_time_overflow() {
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+    assert_eq!(time.checked_add(Duration::from_secs(u64::MAX)), None);
+    assert_eq!(time.checked_sub(Duration::from_secs(2)), None);
+    assert_eq!(time.checked_sub(Duration::from_secs(1)), Some(SystemTime::UNIX_EPOCH));
+}
@@ -8,11 +8,13 @@ extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use sos::{serial_print, serial_println};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     sos::init(boot_info);
+    serial_println!("heap backend: {}", sos::allocator::BACKEND);
 
     tests_main();
     loop {}
@@ -25,6 +27,15 @@ fn panic(info: &PanicInfo) -> ! {
 
 use alloc::boxed::Box;
 
+fn timed<F: FnOnce()>(f: F) {
+    use core::arch::x86_64::_rdtsc;
+
+    let start = unsafe { _rdtsc() };
+    f();
+    let end = unsafe { _rdtsc() };
+    serial_print!("({} cycles) ", end - start);
+}
+
 #[test_case]
 fn simple_allocation() {
     let heap_value_1 = Box::new(41);
@@ -37,21 +48,40 @@ use alloc::vec::Vec;
 
 #[test_case]
 fn large_vec() {
-    let n = 1000;
-    let mut vec = Vec::new();
-    for i in 0..n {
-        vec.push(i);
-    }
-    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    timed(|| {
+        let n = 1000;
+        let mut vec = Vec::new();
+        for i in 0..n {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    });
 }
 
 use sos::allocator::HEAP_SIZE;
 
 #[test_case]
 fn many_boxes() {
-    for i in 0..HEAP_SIZE {
-        let x = Box::new(i);
-        assert_eq!(*x, i);
-    }
+    timed(|| {
+        for i in 0..HEAP_SIZE {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+    });
+}
+
+#[test_case]
+fn heap_stats() {
+    use sos::allocator::heap_stats;
+
+    let before = heap_stats();
+    assert_eq!(before.heap_size, HEAP_SIZE);
+    let x = Box::new([0u8; 100]);
+    let during = heap_stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.bytes_in_use >= before.bytes_in_use + 100);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    drop(x);
+    assert_eq!(heap_stats().deallocations, before.deallocations + 1);
 }
 